@@ -0,0 +1,46 @@
+//! Measure moving data through the in-process pipes of the preview2 streams.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wasmtime_wasi::preview2::pipe::pipe;
+use wasmtime_wasi::preview2::{InputStream, OutputStream};
+
+criterion_group!(benches, bench_pipe);
+criterion_main!(benches);
+
+fn bench_pipe(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("preview2/pipe");
+    for size in [16, 4096, 65536] {
+        let message = vec![0x5a; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        // The reader's buffer fits each message, as when a guest reads into
+        // a buffer at least as large as the host's writes.
+        group.bench_with_input(BenchmarkId::new("whole", size), &message, |b, message| {
+            let (mut input, mut output) = pipe(1);
+            let mut buf = vec![0; size];
+            b.iter(|| {
+                rt.block_on(async {
+                    output.write(message).await.unwrap();
+                    input.read(&mut buf).await.unwrap();
+                })
+            })
+        });
+
+        // The reader takes each message in two halves.
+        group.bench_with_input(BenchmarkId::new("split", size), &message, |b, message| {
+            let (mut input, mut output) = pipe(1);
+            let mut buf = vec![0; size / 2];
+            b.iter(|| {
+                rt.block_on(async {
+                    output.write(message).await.unwrap();
+                    input.read(&mut buf).await.unwrap();
+                    input.read(&mut buf).await.unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
@@ -77,6 +77,22 @@ async fn hello_stdout() -> Result<()> {
         .map_err(|()| anyhow::anyhow!("command returned with failing exit status"))
 }
 
+#[test_log::test(tokio::test)]
+async fn hello_stdout_captured() -> Result<()> {
+    let mut table = Table::new();
+    let (builder, captured) = WasiCtxBuilder::new().with_captured_stdio();
+    let wasi = builder.build(&mut table)?;
+    let (mut store, command) =
+        instantiate(get_component("hello_stdout"), CommandCtx { table, wasi }).await?;
+    command
+        .call_run(&mut store)
+        .await?
+        .map_err(|()| anyhow::anyhow!("command returned with failing exit status"))?;
+    assert_eq!(captured.stdout_contents(), b"hello, world\n");
+    assert_eq!(captured.stderr_contents(), b"hello, world\n");
+    Ok(())
+}
+
 #[test_log::test(tokio::test)]
 async fn panic() -> Result<()> {
     let mut table = Table::new();
@@ -0,0 +1,329 @@
+//! Adapters to `futures` streams and sinks.
+//!
+//! These let a guest read from an asynchronous [`Stream`] of byte chunks, or
+//! write to a [`Sink`] of them, so that sources and destinations written
+//! against the `futures` traits can back WASI streams.
+//!
+//! Only available with the `futures` feature.
+//!
+//! [`Stream`]: futures_core::Stream
+//! [`Sink`]: futures_sink::Sink
+
+use crate::preview2::stream::{InputStream, OutputStream};
+use anyhow::Error;
+use std::any::Any;
+use std::convert::TryInto;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+/// An input stream reading from an asynchronous [`Stream`] of byte chunks,
+/// such as an HTTP request body.
+///
+/// Reads wait for the next chunk when none is buffered, and a chunk larger
+/// than the guest's buffer is delivered over several reads. An error from the
+/// source is returned from the read that reaches it, and the end of the
+/// source is the end of the stream.
+///
+/// [`Stream`]: futures_core::Stream
+pub struct StreamInputStream<S> {
+    // Guarded by a mutex for `Sync`, as with the senders of
+    // `pipe::ChannelOutputStream`.
+    source: Mutex<Pin<Box<S>>>,
+    /// The unread remainder of the chunk most recently taken from the source.
+    buffer: Vec<u8>,
+    ended: bool,
+}
+
+impl<S> StreamInputStream<S>
+where
+    S: futures_core::Stream<Item = Result<Vec<u8>, Error>> + Send + 'static,
+{
+    /// Create a stream reading the chunks `source` yields, in order.
+    ///
+    /// Empty chunks are skipped, and the stream ends when `source` does.
+    pub fn new(source: S) -> Self {
+        Self {
+            source: Mutex::new(Box::pin(source)),
+            buffer: Vec::new(),
+            ended: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> InputStream for StreamInputStream<S>
+where
+    S: futures_core::Stream<Item = Result<Vec<u8>, Error>> + Send + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        while self.buffer.is_empty() {
+            if self.ended {
+                return Ok((0, true));
+            }
+            let source = self.source.get_mut().unwrap();
+            match std::future::poll_fn(|cx| source.as_mut().poll_next(cx)).await {
+                Some(chunk) => self.buffer = chunk?,
+                None => self.ended = true,
+            }
+        }
+        let n = buf.len().min(self.buffer.len());
+        buf[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        Ok((n.try_into()?, false))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.buffer.len().try_into()?)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An output stream writing to an asynchronous [`Sink`] of byte chunks, such
+/// as a framed codec or a websocket.
+///
+/// Each write sends the written bytes to the sink as one chunk. A write made
+/// while the sink isn't ready to take a chunk accepts zero bytes, and
+/// [`writable`](OutputStream::writable) waits until it is. The sink may hold
+/// chunks back; syncing the stream flushes it, and shutting the stream down
+/// closes it. Once the sink fails, every later write fails too, since the
+/// sink can't be relied on any more.
+///
+/// [`Sink`]: futures_sink::Sink
+pub struct SinkOutputStream<S> {
+    // Guarded by a mutex for `Sync`, as with the senders of
+    // `pipe::ChannelOutputStream`. It is only contended by `writable`.
+    sink: Mutex<Pin<Box<S>>>,
+    failed: bool,
+}
+
+impl<S> SinkOutputStream<S>
+where
+    S: futures_sink::Sink<Vec<u8>> + Send + 'static,
+    S::Error: Into<Error>,
+{
+    /// Create a stream sending each write to `sink` as one chunk.
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink: Mutex::new(Box::pin(sink)),
+            failed: false,
+        }
+    }
+
+    /// Run `op` on the sink, remembering whether it failed.
+    async fn with_sink<R>(
+        &mut self,
+        mut op: impl FnMut(Pin<&mut S>, &mut Context<'_>) -> Poll<Result<R, S::Error>>,
+    ) -> Result<R, Error> {
+        if self.failed {
+            anyhow::bail!("sink failed earlier");
+        }
+        let sink = self.sink.get_mut().unwrap();
+        let result = std::future::poll_fn(|cx| op(sink.as_mut(), cx)).await;
+        self.failed = result.is_err();
+        result.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> OutputStream for SinkOutputStream<S>
+where
+    S: futures_sink::Sink<Vec<u8>> + Send + 'static,
+    S::Error: Into<Error>,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Check whether the sink is ready only once, rather than waiting for
+        // it, so that a back-pressured sink doesn't hold up the guest.
+        let ready = self
+            .with_sink(|sink, cx| Poll::Ready(Ok(sink.poll_ready(cx))))
+            .await?;
+        match ready {
+            Poll::Pending => return Ok(0),
+            Poll::Ready(Err(err)) => {
+                self.failed = true;
+                return Err(err.into());
+            }
+            Poll::Ready(Ok(())) => {}
+        }
+        let sink = self.sink.get_mut().unwrap();
+        if let Err(err) = sink.as_mut().start_send(buf.to_vec()) {
+            self.failed = true;
+            return Err(err.into());
+        }
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.with_sink(|sink, cx| sink.poll_close(cx)).await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.with_sink(|sink, cx| sink.poll_flush(cx)).await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        if self.failed {
+            // The next write reports the failure.
+            return Ok(());
+        }
+        std::future::poll_fn(|cx| self.sink.lock().unwrap().as_mut().poll_ready(cx))
+            .await
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::stream::test_util::{poll_once, Gated};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[cfg(feature = "futures")]
+    #[tokio::test]
+    async fn stream_input_stream() {
+        struct Chunks(VecDeque<Result<Vec<u8>, Error>>);
+
+        impl futures_core::Stream for Chunks {
+            type Item = Result<Vec<u8>, Error>;
+
+            fn poll_next(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+            ) -> Poll<Option<Self::Item>> {
+                Poll::Ready(self.0.pop_front())
+            }
+        }
+
+        let mut stream = StreamInputStream::new(Chunks(VecDeque::from([
+            Ok(b"hello".to_vec()),
+            Ok(Vec::new()),
+            Ok(b"!".to_vec()),
+            Err(anyhow::anyhow!("connection reset")),
+        ])));
+        let mut buf = [0; 3];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (1, false));
+        assert!(stream.read(&mut buf).await.is_err());
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn sink_output_stream_sends_chunks() {
+        /// A sink that records its chunks and whether it was closed, and fails
+        /// to send `bad`.
+        #[derive(Clone, Default)]
+        struct Record(Arc<Mutex<(Vec<Vec<u8>>, bool)>>);
+
+        impl futures_sink::Sink<Vec<u8>> for Record {
+            type Error = io::Error;
+
+            fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn start_send(self: Pin<&mut Self>, chunk: Vec<u8>) -> io::Result<()> {
+                if chunk == b"bad" {
+                    return Err(io::Error::new(io::ErrorKind::Other, "bad chunk"));
+                }
+                self.0.lock().unwrap().0.push(chunk);
+                Ok(())
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.0.lock().unwrap().1 = true;
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let record = Record::default();
+        let mut stream = SinkOutputStream::new(record.clone());
+        assert_eq!(stream.write(b"one").await.unwrap(), 3);
+        assert_eq!(stream.write(b"two").await.unwrap(), 3);
+        stream.sync_data().await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(
+            *record.0.lock().unwrap(),
+            (vec![b"one".to_vec(), b"two".to_vec()], true)
+        );
+
+        let mut stream = SinkOutputStream::new(Record::default());
+        assert!(stream.write(b"bad").await.is_err());
+        assert!(stream.write(b"good").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sink_output_stream_doesnt_wait_for_the_sink() {
+        /// A sink that is only ready when the test says so.
+        struct Slow(Arc<AtomicBool>);
+
+        impl futures_sink::Sink<Vec<u8>> for Slow {
+            type Error = anyhow::Error;
+
+            fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+                if self.0.load(Ordering::SeqCst) {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                }
+            }
+
+            fn start_send(self: Pin<&mut Self>, _chunk: Vec<u8>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let ready = Arc::new(AtomicBool::new(false));
+        let mut stream = SinkOutputStream::new(Slow(ready.clone()));
+        assert_eq!(stream.write(b"held").await.unwrap(), 0);
+        {
+            let mut writable = stream.writable();
+            assert!(poll_once(&mut writable).await.is_pending());
+            ready.store(true, Ordering::SeqCst);
+            assert!(poll_once(&mut writable).await.is_ready());
+        }
+        assert_eq!(stream.write(b"sent").await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_read_loses_nothing() {
+        let chunks = Arc::new(Mutex::new(VecDeque::new()));
+        let mut stream = StreamInputStream::new(Gated(chunks.clone()));
+        let mut buf = [0; 16];
+        assert!(poll_once(&mut stream.read(&mut buf)).await.is_pending());
+
+        chunks.lock().unwrap().push_back(b"hello".to_vec());
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"hello");
+    }
+}
@@ -0,0 +1,414 @@
+//! Capturing output streams.
+//!
+//! The streams in this module keep what a guest writes for the host to look
+//! at, e.g. in logs or tests: as one `tracing` event per line, as a bounded
+//! prefix of the output, or as one shared record of several streams in the
+//! order their writes happened.
+
+use crate::preview2::clocks::WasiMonotonicClock;
+use crate::preview2::stream::OutputStream;
+use anyhow::Error;
+use std::any::Any;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+/// An output stream that logs each line written to it as a `tracing` event.
+///
+/// Bytes are buffered until a newline is written, and each complete line is
+/// emitted as an event at the configured level, so guest stdout or stderr
+/// becomes part of the host's structured logging. Any incomplete final line is
+/// emitted when the stream is dropped.
+///
+/// To bound the memory a guest can make the host hold, a line longer than
+/// [`max_line_len`](Self::max_line_len) bytes is split, and each
+/// `max_line_len` bytes of it are emitted as a separate event as soon as they
+/// arrive, without waiting for the newline.
+///
+/// Since `tracing` requires event targets to be known statically, all events
+/// use this module's target and carry the configured name in a `stream` field.
+pub struct TracingOutputStream {
+    level: tracing::Level,
+    name: String,
+    buffer: Vec<u8>,
+    max_line_len: usize,
+}
+
+/// The default [`TracingOutputStream::max_line_len`].
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Remove each complete line from the start of `buffer` and pass it to `emit`
+/// without its newline, splitting lines longer than `max_line_len`.
+fn take_lines(buffer: &mut Vec<u8>, max_line_len: usize, mut emit: impl FnMut(&[u8])) {
+    loop {
+        match buffer.iter().position(|b| *b == b'\n') {
+            Some(newline) if newline <= max_line_len => {
+                emit(&buffer[..newline]);
+                buffer.drain(..=newline);
+            }
+            _ if buffer.len() > max_line_len => {
+                emit(&buffer[..max_line_len]);
+                buffer.drain(..max_line_len);
+            }
+            _ => break,
+        }
+    }
+}
+
+impl TracingOutputStream {
+    /// Create a stream logging lines at `level`, labelled with `name`, e.g.
+    /// `"stdout"`.
+    pub fn new(level: tracing::Level, name: impl Into<String>) -> Self {
+        Self {
+            level,
+            name: name.into(),
+            buffer: Vec::new(),
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+        }
+    }
+
+    /// Set the longest line, in bytes, emitted as a single event. Defaults to
+    /// [`DEFAULT_MAX_LINE_LEN`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_line_len` is zero.
+    pub fn max_line_len(mut self, max_line_len: usize) -> Self {
+        assert!(max_line_len > 0, "max line length must be nonzero");
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    fn log_line(&self, line: &[u8]) {
+        use tracing::Level;
+        let line = String::from_utf8_lossy(line);
+        let stream = self.name.as_str();
+        if self.level == Level::ERROR {
+            tracing::error!(stream, "{line}");
+        } else if self.level == Level::WARN {
+            tracing::warn!(stream, "{line}");
+        } else if self.level == Level::INFO {
+            tracing::info!(stream, "{line}");
+        } else if self.level == Level::DEBUG {
+            tracing::debug!(stream, "{line}");
+        } else {
+            tracing::trace!(stream, "{line}");
+        }
+    }
+}
+
+impl Drop for TracingOutputStream {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.log_line(&self.buffer);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for TracingOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        self.buffer.extend_from_slice(buf);
+        let mut buffer = std::mem::take(&mut self.buffer);
+        take_lines(&mut buffer, self.max_line_len, |line| self.log_line(line));
+        self.buffer = buffer;
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            self.log_line(&self.buffer);
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An output stream that captures the first `limit` bytes written to it and
+/// discards the rest.
+///
+/// Writes always report that all of their bytes were accepted, so a guest is
+/// never blocked by the limit. Clones share the same captured contents, so a
+/// clone can be kept to inspect the output after another is handed to a
+/// `WasiCtx`.
+#[derive(Debug, Clone)]
+pub struct HeadOutputStream {
+    contents: Arc<Mutex<Vec<u8>>>,
+    limit: usize,
+}
+
+impl HeadOutputStream {
+    /// Create a stream capturing at most `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            contents: Arc::new(Mutex::new(Vec::new())),
+            limit,
+        }
+    }
+
+    /// The bytes captured so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.contents.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for HeadOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let mut contents = self.contents.lock().unwrap();
+        let n = buf.len().min(self.limit - contents.len());
+        contents.extend_from_slice(&buf[..n]);
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A single write captured by a [`MultiplexOutputStream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputRecord {
+    /// The label of the stream the bytes were written to.
+    pub label: String,
+    /// The bytes written.
+    pub bytes: Vec<u8>,
+    /// The monotonic clock's time at which they were written.
+    pub timestamp: u64,
+}
+
+/// A structured capture of writes to several labelled output streams.
+///
+/// Each stream created with [`handle`](Self::handle) records its writes,
+/// tagged with its label and the time according to the given clock, into a
+/// log shared by all of them. Unlike capturing every stream into a single
+/// buffer, this keeps the interleaving of writes available for programmatic
+/// analysis through [`records`](Self::records).
+#[derive(Clone)]
+pub struct MultiplexOutputStream {
+    clock: Arc<dyn WasiMonotonicClock>,
+    log: Arc<Mutex<MultiplexLog>>,
+    max_buffer: usize,
+}
+
+#[derive(Default)]
+struct MultiplexLog {
+    records: Vec<OutputRecord>,
+    /// The total length of the bytes in `records`.
+    bytes: usize,
+}
+
+impl MultiplexOutputStream {
+    /// Create an empty log timestamped by `clock`.
+    pub fn new(clock: impl WasiMonotonicClock + 'static) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            log: Arc::new(Mutex::new(MultiplexLog::default())),
+            max_buffer: usize::MAX,
+        }
+    }
+
+    /// Record at most `limit` bytes in total. Nothing takes records out of
+    /// the log, so rather than wait for room, a write to a full log fails,
+    /// and a write that does not fit is recorded only in part.
+    ///
+    /// This applies to the streams created by [`handle`](Self::handle)
+    /// afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn with_max_buffer(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "max buffer must be nonzero");
+        self.max_buffer = limit;
+        self
+    }
+
+    /// Create an output stream whose writes are recorded under `label`.
+    pub fn handle(&self, label: impl Into<String>) -> LabeledOutputStream {
+        LabeledOutputStream {
+            label: label.into(),
+            log: self.clone(),
+        }
+    }
+
+    /// The writes recorded so far, in the order they happened.
+    pub fn records(&self) -> Vec<OutputRecord> {
+        self.log.lock().unwrap().records.clone()
+    }
+}
+
+/// An output stream recording into a [`MultiplexOutputStream`].
+pub struct LabeledOutputStream {
+    label: String,
+    log: MultiplexOutputStream,
+}
+
+#[async_trait::async_trait]
+impl OutputStream for LabeledOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut log = self.log.log.lock().unwrap();
+        let n = buf.len().min(self.log.max_buffer.saturating_sub(log.bytes));
+        if n == 0 {
+            anyhow::bail!("output log is full");
+        }
+        let record = OutputRecord {
+            label: self.label.clone(),
+            bytes: buf[..n].to_vec(),
+            timestamp: self.log.clock.now(),
+        };
+        log.records.push(record);
+        log.bytes += n;
+        Ok(n.try_into()?)
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+
+    #[tokio::test]
+    async fn head_output_stream_keeps_first_bytes() {
+        let head = HeadOutputStream::new(6);
+        let mut stream = head.clone();
+        assert_eq!(stream.write(b"banner").await.unwrap(), 6);
+        assert_eq!(stream.write(b" and more").await.unwrap(), 9);
+        assert_eq!(head.contents(), b"banner");
+
+        let head = HeadOutputStream::new(4);
+        let mut stream = head.clone();
+        assert_eq!(stream.write(b"ab").await.unwrap(), 2);
+        assert_eq!(stream.write(b"cdef").await.unwrap(), 4);
+        assert_eq!(head.contents(), b"abcd");
+    }
+
+    #[tokio::test]
+    async fn multiplex_output_stream_records() {
+        let clock = TickClock::new(0);
+        let log = MultiplexOutputStream::new(clock.clone());
+        let mut stdout = log.handle("stdout");
+        let mut stderr = log.handle("stderr");
+
+        clock.set(1);
+        stdout.write(b"out").await.unwrap();
+        clock.set(2);
+        stderr.write(b"err").await.unwrap();
+        stdout.write(b"").await.unwrap();
+
+        let record = |label: &str, bytes: &[u8], timestamp| OutputRecord {
+            label: label.to_string(),
+            bytes: bytes.to_vec(),
+            timestamp,
+        };
+        assert_eq!(
+            log.records(),
+            vec![record("stdout", b"out", 1), record("stderr", b"err", 2)]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut buffer = vec![b'x'; 100 * 1024];
+        buffer.extend_from_slice(b"\nshort\ntail");
+        let mut lines = Vec::new();
+        take_lines(&mut buffer, 64 * 1024, |line| lines.push(line.len()));
+        assert_eq!(lines, [64 * 1024, 36 * 1024, 5]);
+        assert_eq!(buffer, b"tail");
+    }
+
+    #[tokio::test]
+    async fn multiplex_output_stream_max_buffer() {
+        let log = MultiplexOutputStream::new(TickClock::new(0)).with_max_buffer(4);
+        let mut stdout = log.handle("stdout");
+        let mut stderr = log.handle("stderr");
+        assert_eq!(stdout.write(b"out").await.unwrap(), 3);
+        assert_eq!(stderr.write(b"err").await.unwrap(), 1);
+        assert!(stdout.write(b"more").await.is_err());
+        let recorded: Vec<u8> = log.records().into_iter().flat_map(|r| r.bytes).collect();
+        assert_eq!(recorded, b"oute");
+    }
+
+    /// A `tracing` subscriber recording the level, `stream` field and message
+    /// of each event.
+    #[derive(Clone, Default)]
+    struct RecordEvents(Arc<Mutex<Vec<(tracing::Level, String, String)>>>);
+
+    impl tracing::Subscriber for RecordEvents {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            #[derive(Default)]
+            struct Fields(String, String);
+            impl tracing::field::Visit for Fields {
+                fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                    if field.name() == "stream" {
+                        self.0 = value.to_string();
+                    }
+                }
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.1 = format!("{value:?}");
+                    }
+                }
+            }
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), fields.0, fields.1));
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn tracing_output_emits_one_event_per_line() {
+        let events = RecordEvents::default();
+        let _guard = tracing::subscriber::set_default(events.clone());
+
+        let mut stream = TracingOutputStream::new(tracing::Level::WARN, "stderr");
+        assert_eq!(stream.write(b"one\ntw").await.unwrap(), 6);
+        assert_eq!(stream.write(b"o\nthree").await.unwrap(), 7);
+        drop(stream);
+
+        let warn = |line: &str| (tracing::Level::WARN, "stderr".to_string(), line.to_string());
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [warn("one"), warn("two"), warn("three")]
+        );
+    }
+}
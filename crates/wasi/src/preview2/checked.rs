@@ -0,0 +1,262 @@
+//! Protocol checks for stream implementations.
+//!
+//! The wrappers in this module forward every operation to an inner stream
+//! and check that its results keep the stream protocol, e.g. that a read
+//! never claims more bytes than fit in the buffer and that no data follows
+//! the end of a stream. They help catch bugs in custom [`InputStream`] and
+//! [`OutputStream`] implementations during development.
+//!
+//! A broken invariant panics with a message naming the inner stream's type.
+//! The checks are `debug_assert!`s, so in release builds the wrappers only
+//! forward. A stream that reports it is ready but then moves no data is not
+//! broken, since readiness may be spurious, so that is only logged, once per
+//! stream.
+
+use crate::preview2::stream::{
+    forward_input_stream, forward_output_stream, InputStream, OutputStream,
+};
+use anyhow::Error;
+use std::any::{type_name, Any};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// An input stream wrapper that checks the results of an inner stream.
+pub struct CheckedInputStream<T> {
+    inner: T,
+    /// Whether the inner stream has reported the end of the stream.
+    ended: bool,
+    /// Whether `readable` returned since the last read.
+    readable: AtomicBool,
+    warned: bool,
+}
+
+impl<T: InputStream> CheckedInputStream<T> {
+    /// Create a wrapper checking every read of `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            ended: false,
+            readable: AtomicBool::new(false),
+            warned: false,
+        }
+    }
+
+    fn check(&mut self, op: &str, requested: u64, (n, end): (u64, bool)) {
+        debug_assert!(
+            n <= requested,
+            "{}::{op} returned {n} bytes, more than the {requested} asked for",
+            type_name::<T>(),
+        );
+        debug_assert!(
+            !self.ended || n == 0,
+            "{}::{op} returned {n} bytes after reporting the end of the stream",
+            type_name::<T>(),
+        );
+        let readable = self.readable.swap(false, Ordering::Relaxed);
+        if readable && n == 0 && !end && requested != 0 && !self.warned {
+            tracing::warn!(
+                "{} reported it was readable, but {op} returned no data",
+                type_name::<T>(),
+            );
+            self.warned = true;
+        }
+        self.ended |= end;
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for CheckedInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let result = self.inner.read(buf).await?;
+        self.check("read", buf.len().try_into()?, result);
+        Ok(result)
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        bufs: &mut [std::io::IoSliceMut<'a>],
+    ) -> Result<(u64, bool), Error> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let result = self.inner.read_vectored(bufs).await?;
+        self.check("read_vectored", len.try_into()?, result);
+        Ok(result)
+    }
+
+    fn is_read_vectored(&self) -> bool {
+        self.inner.is_read_vectored()
+    }
+
+    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
+        let result = self.inner.skip(nelem).await?;
+        self.check("skip", nelem, result);
+        Ok(result)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await?;
+        self.readable.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// An output stream wrapper that checks the results of an inner stream.
+pub struct CheckedOutputStream<T> {
+    inner: T,
+    /// Whether `shutdown` has succeeded.
+    shut_down: bool,
+    /// Whether `writable` returned since the last write.
+    writable: AtomicBool,
+    warned: bool,
+}
+
+impl<T: OutputStream> CheckedOutputStream<T> {
+    /// Create a wrapper checking every write of `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            shut_down: false,
+            writable: AtomicBool::new(false),
+            warned: false,
+        }
+    }
+
+    fn check(&mut self, op: &str, requested: u64, n: u64) {
+        debug_assert!(
+            n <= requested,
+            "{}::{op} accepted {n} bytes, more than the {requested} given",
+            type_name::<T>(),
+        );
+        debug_assert!(
+            !self.shut_down || n == 0,
+            "{}::{op} accepted {n} bytes after it was shut down",
+            type_name::<T>(),
+        );
+        let writable = self.writable.swap(false, Ordering::Relaxed);
+        if writable && n == 0 && requested != 0 && !self.warned {
+            tracing::warn!(
+                "{} reported it was writable, but {op} accepted no data",
+                type_name::<T>(),
+            );
+            self.warned = true;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for CheckedOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        self.check("write", buf.len().try_into()?, n);
+        Ok(n)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let n = self.inner.write_vectored(bufs).await?;
+        self.check("write_vectored", len.try_into()?, n);
+        Ok(n)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    async fn splice(
+        &mut self,
+        src: &mut dyn InputStream,
+        nelem: u64,
+    ) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.splice(src, nelem).await?;
+        self.check("splice", nelem, n);
+        Ok((n, end))
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        let n = self.inner.write_zeroes(nelem).await?;
+        self.check("write_zeroes", nelem, n);
+        Ok(n)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await?;
+        self.shut_down = true;
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await?;
+        self.writable.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::pipe::{pipe, ReadPipe};
+
+    /// An input stream that claims to have read more than it was asked for.
+    struct Overread;
+
+    #[async_trait::async_trait]
+    impl InputStream for Overread {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+            Ok((buf.len() as u64 + 1, false))
+        }
+        async fn readable(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn well_behaved_streams_pass() {
+        let mut input = CheckedInputStream::new(ReadPipe::from("hello"));
+        let mut buf = [0; 16];
+        input.readable().await.unwrap();
+        assert_eq!(input.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+
+        let (_input, output) = pipe(1);
+        let mut output = CheckedOutputStream::new(output);
+        assert_eq!(output.write(b"one").await.unwrap(), 3);
+        output.writable().await.unwrap();
+        // Readiness may be spurious; this is only logged.
+        assert_eq!(output.write(b"two").await.unwrap(), 0);
+        output.shutdown().await.unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "more than the 4 asked for")]
+    async fn overlong_read_panics() {
+        let mut input = CheckedInputStream::new(Overread);
+        let _ = input.read(&mut [0; 4]).await;
+    }
+}
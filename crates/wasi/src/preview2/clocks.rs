@@ -1,5 +1,10 @@
 pub mod host;
 use cap_std::time::Duration;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::task::{Poll, Waker};
+use std::time::Instant;
 
 pub trait WasiWallClock: Send + Sync {
     fn resolution(&self) -> Duration;
@@ -11,7 +16,694 @@ pub trait WasiMonotonicClock: Send + Sync {
     fn now(&self) -> u64;
 }
 
+/// Errors produced by the clocks implementation.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    #[error("time is before the Unix epoch")]
+    BeforeEpoch,
+    #[error("time is out of range")]
+    Overflow,
+    #[error("nanoseconds field is not less than 1000000000")]
+    InvalidNanoseconds,
+    #[error("unknown timezone")]
+    UnknownTimezone,
+    #[error("invalid RFC 3339 timestamp")]
+    InvalidFormat,
+    #[error("invalid POSIX TZ string")]
+    InvalidTimezone,
+}
+
 pub struct WasiClocks {
     pub wall: Box<dyn WasiWallClock + Send + Sync>,
     pub monotonic: Box<dyn WasiMonotonicClock + Send + Sync>,
 }
+
+impl WasiClocks {
+    /// Create a set of clocks from a wall clock and a monotonic clock.
+    pub fn new(
+        wall: impl WasiWallClock + 'static,
+        monotonic: impl WasiMonotonicClock + 'static,
+    ) -> Self {
+        Self {
+            wall: Box::new(wall),
+            monotonic: Box::new(monotonic),
+        }
+    }
+}
+
+/// A host-side timezone, stored in the `Table` behind a guest's `timezone`
+/// handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timezone {
+    name: String,
+    utc_offset: i32,
+    dst: Option<Dst>,
+}
+
+/// The daylight saving time of a [`Timezone`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dst {
+    name: String,
+    utc_offset: i32,
+    /// When daylight saving time starts, in local standard time.
+    start: Transition,
+    /// When daylight saving time ends, in local daylight saving time.
+    end: Transition,
+}
+
+/// A yearly change between standard and daylight saving time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transition {
+    date: TransitionDate,
+    /// The time of day of the change, in seconds after midnight. POSIX
+    /// allows this to be negative or past the end of the day.
+    time: i32,
+}
+
+/// The day of a [`Transition`], in the forms a POSIX `TZ` string allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransitionDate {
+    /// `Jn`: day `n` of the year, from 1 to 365, never counting February 29.
+    Julian(u16),
+    /// `n`: day `n` of the year, from 0 to 365, counting February 29.
+    ZeroBased(u16),
+    /// `Mm.w.d`: weekday `d`, from 0 for Sunday, of week `w` of month `m`,
+    /// where week 5 is the last one of the month.
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+}
+
+impl TransitionDate {
+    /// The day of this date in `year`, as a number of days after 1970-01-01.
+    fn day(self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match self {
+            TransitionDate::Julian(n) => {
+                let leap_day = days_in_month(year, 2) == 29 && n >= 60;
+                jan1 + i64::from(n) - 1 + i64::from(leap_day)
+            }
+            TransitionDate::ZeroBased(n) => jan1 + i64::from(n),
+            TransitionDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = days_from_civil(year, month, 1);
+                // 1970-01-01 was a Thursday.
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = first
+                    + (i64::from(weekday) - first_weekday).rem_euclid(7)
+                    + 7 * (i64::from(week) - 1);
+                let last = first + i64::from(days_in_month(year, month)) - 1;
+                while day > last {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+impl Timezone {
+    /// Create a timezone with a fixed offset, in seconds east of UTC.
+    pub fn new(name: impl Into<String>, utc_offset: i32) -> Self {
+        Self {
+            name: name.into(),
+            utc_offset,
+            dst: None,
+        }
+    }
+
+    /// Coordinated Universal Time, which is also what WASI specifies for
+    /// implementations that do not expose an actual time zone.
+    pub fn utc() -> Self {
+        Self::new("UTC", 0)
+    }
+
+    /// Parse a timezone from a POSIX `TZ` string, such as
+    /// `EST5EDT,M3.2.0,M11.1.0` or `<+1030>-10:30<+11>-11,M10.1.0,M4.1.0`.
+    ///
+    /// The string gives the rules for changing to and from daylight saving
+    /// time, so no timezone database is needed. As in POSIX, offsets count
+    /// hours west of UTC. A timezone with a daylight saving time but no rules
+    /// uses the current US rules, `M3.2.0,M11.1.0`, like glibc does. Strings
+    /// naming a timezone in a database, such as `:Europe/Paris`, aren't
+    /// supported and fail with [`Error::UnknownTimezone`].
+    pub fn from_posix_tz(tz: &str) -> Result<Self, Error> {
+        if tz.starts_with(':') {
+            return Err(Error::UnknownTimezone);
+        }
+        PosixTz(tz.as_bytes())
+            .timezone()
+            .ok_or(Error::InvalidTimezone)
+    }
+
+    /// The name this timezone was configured with, e.g. `UTC` or `CET`. For
+    /// a timezone with daylight saving time, this is the standard time name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of seconds east of UTC. For a timezone with daylight
+    /// saving time, this is the standard time offset.
+    pub fn utc_offset(&self) -> i32 {
+        self.utc_offset
+    }
+
+    /// Whether daylight saving time is in effect `seconds` after the Unix
+    /// epoch.
+    pub fn is_daylight_saving_time(&self, seconds: u64) -> bool {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return false,
+        };
+        let seconds = i128::from(seconds);
+        let local_days = (seconds + i128::from(self.utc_offset)).div_euclid(86_400);
+        let (year, _, _) = civil_from_days(local_days as i64);
+        let at = |transition: Transition, utc_offset: i32| {
+            i128::from(transition.date.day(year)) * 86_400 + i128::from(transition.time)
+                - i128::from(utc_offset)
+        };
+        let start = at(dst.start, self.utc_offset);
+        let end = at(dst.end, dst.utc_offset);
+        if start <= end {
+            start <= seconds && seconds < end
+        } else {
+            // Southern hemisphere: daylight saving time spans the new year.
+            seconds < end || start <= seconds
+        }
+    }
+
+    /// The number of seconds east of UTC in effect `seconds` after the Unix
+    /// epoch.
+    pub fn utc_offset_at(&self, seconds: u64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_daylight_saving_time(seconds) => dst.utc_offset,
+            _ => self.utc_offset,
+        }
+    }
+
+    /// The name in effect `seconds` after the Unix epoch.
+    pub fn name_at(&self, seconds: u64) -> &str {
+        match &self.dst {
+            Some(dst) if self.is_daylight_saving_time(seconds) => &dst.name,
+            _ => &self.name,
+        }
+    }
+}
+
+/// A cursor over the bytes of a POSIX `TZ` string being parsed.
+struct PosixTz<'a>(&'a [u8]);
+
+impl PosixTz<'_> {
+    fn timezone(&mut self) -> Option<Timezone> {
+        let name = self.name()?;
+        let utc_offset = -self.time(24)?;
+        if self.0.is_empty() {
+            return Some(Timezone::new(name, utc_offset));
+        }
+        let dst_name = self.name()?;
+        let dst_offset = match self.0.first() {
+            None | Some(b',') => utc_offset + 3600,
+            Some(_) => -self.time(24)?,
+        };
+        let (start, end) = if self.0.is_empty() {
+            PosixTz(b",M3.2.0,M11.1.0").rules()?
+        } else {
+            self.rules()?
+        };
+        if !self.0.is_empty() {
+            return None;
+        }
+        Some(Timezone {
+            name,
+            utc_offset,
+            dst: Some(Dst {
+                name: dst_name,
+                utc_offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    fn byte(&mut self, accept: impl Fn(u8) -> bool) -> Option<u8> {
+        let (&b, rest) = self.0.split_first()?;
+        if !accept(b) {
+            return None;
+        }
+        self.0 = rest;
+        Some(b)
+    }
+
+    /// A name of at least three letters, or of at least three letters,
+    /// digits and signs between angle brackets.
+    fn name(&mut self) -> Option<String> {
+        let quoted = self.byte(|b| b == b'<').is_some();
+        let mut name = String::new();
+        while let Some(b) = self.byte(|b| {
+            b.is_ascii_alphabetic() || (quoted && (b.is_ascii_digit() || b == b'+' || b == b'-'))
+        }) {
+            name.push(char::from(b));
+        }
+        if quoted {
+            self.byte(|b| b == b'>')?;
+        }
+        if name.len() < 3 {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// A number of at most `max` with no more than three digits.
+    fn number(&mut self, max: u32) -> Option<u32> {
+        let mut n = u32::from(self.byte(|b| b.is_ascii_digit())? - b'0');
+        for _ in 0..2 {
+            match self.byte(|b| b.is_ascii_digit()) {
+                Some(b) => n = n * 10 + u32::from(b - b'0'),
+                None => break,
+            }
+        }
+        if n > max {
+            return None;
+        }
+        Some(n)
+    }
+
+    /// A signed `hh[:mm[:ss]]` time or offset of at most `max_hours` hours,
+    /// in seconds.
+    fn time(&mut self, max_hours: u32) -> Option<i32> {
+        let negative = match self.byte(|b| b == b'+' || b == b'-') {
+            Some(b) => b == b'-',
+            None => false,
+        };
+        let mut seconds = self.number(max_hours)? * 3600;
+        if self.byte(|b| b == b':').is_some() {
+            seconds += self.number(59)? * 60;
+            if self.byte(|b| b == b':').is_some() {
+                seconds += self.number(59)?;
+            }
+        }
+        let seconds = seconds as i32;
+        Some(if negative { -seconds } else { seconds })
+    }
+
+    /// The `,start[/time],end[/time]` rules for daylight saving time.
+    fn rules(&mut self) -> Option<(Transition, Transition)> {
+        self.byte(|b| b == b',')?;
+        let start = self.transition()?;
+        self.byte(|b| b == b',')?;
+        let end = self.transition()?;
+        Some((start, end))
+    }
+
+    fn transition(&mut self) -> Option<Transition> {
+        let date = if self.byte(|b| b == b'J').is_some() {
+            TransitionDate::Julian(self.number(365).filter(|n| *n >= 1)? as u16)
+        } else if self.byte(|b| b == b'M').is_some() {
+            let month = self.number(12).filter(|m| *m >= 1)?;
+            self.byte(|b| b == b'.')?;
+            let week = self.number(5).filter(|w| *w >= 1)?;
+            self.byte(|b| b == b'.')?;
+            let weekday = self.number(6)?;
+            TransitionDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            TransitionDate::ZeroBased(self.number(365)? as u16)
+        };
+        let time = if self.byte(|b| b == b'/').is_some() {
+            self.time(167)?
+        } else {
+            2 * 3600
+        };
+        Some(Transition { date, time })
+    }
+}
+
+/// The year, month and day of the day `days` after 1970-01-01 in the
+/// proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's `civil_from_days`, which counts from 0000-03-01 so
+    // that the leap day falls at the end of each year.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// The inverse of [`civil_from_days`].
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+pub(crate) fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A monotonic clock for deterministic tests, which advances by a fixed step
+/// each time it is read.
+///
+/// Every call to `now`, including the ones made by `subscribe` and while
+/// polling a timer, returns the current time and then moves it forward by
+/// `step` nanoseconds, so a guest sees the same timings on every run. With a
+/// step of zero, time only moves when [`tick`](Self::tick) is called. Clones
+/// share the same time, so a clone can be kept to drive the clock after
+/// another is handed to a `WasiCtx`.
+#[derive(Debug, Clone)]
+pub struct TickClock {
+    now: Arc<AtomicU64>,
+    step: u64,
+}
+
+impl TickClock {
+    /// Create a clock starting at zero and advancing by `step` nanoseconds
+    /// per reading.
+    pub fn new(step: u64) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(0)),
+            step,
+        }
+    }
+
+    /// Advance the clock by one step, or by one nanosecond if the step is
+    /// zero, returning the new time.
+    pub fn tick(&self) -> u64 {
+        let by = self.step.max(1);
+        self.advance(by).saturating_add(by)
+    }
+
+    /// The current time, without advancing the clock.
+    pub fn peek(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    /// Set the clock to `now`, which should not be before the current time,
+    /// since monotonic clocks never go backwards.
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    /// Move the clock forward by `by`, returning the time before the move.
+    fn advance(&self, by: u64) -> u64 {
+        self.now
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |t| {
+                Some(t.saturating_add(by))
+            })
+            .unwrap()
+    }
+}
+
+impl WasiMonotonicClock for TickClock {
+    fn resolution(&self) -> u64 {
+        self.step.max(1)
+    }
+
+    fn now(&self) -> u64 {
+        self.advance(self.step)
+    }
+}
+
+/// The shortest time [`until_deadline`] waits before checking a clock that
+/// runs behind real time again.
+const MIN_RECHECK: Duration = Duration::from_millis(1);
+
+/// The longest time [`until_deadline`] waits before checking a clock that
+/// runs behind real time again.
+const MAX_RECHECK: Duration = Duration::from_millis(100);
+
+/// Run `future` until it finishes, returning its output, or until `clock`
+/// reaches `deadline`, returning `None`.
+///
+/// While `future` is pending, a host thread shared by all such waits wakes
+/// the task when the deadline should have passed, taking the clock's
+/// nanoseconds to be real ones. A clock that runs behind real time, such as
+/// a [`TickClock`], is checked again after waits that double in length, from
+/// `MIN_RECHECK` up to `MAX_RECHECK`, so this never spins.
+pub(crate) async fn until_deadline<F: Future>(
+    future: F,
+    clock: &dyn WasiMonotonicClock,
+    deadline: u64,
+) -> Option<F::Output> {
+    let mut future = Box::pin(future);
+    let mut backoff = Duration::ZERO;
+    // When the last timer fires, and the waker it wakes. Dropping the timer,
+    // including when this future is dropped, cancels it.
+    let mut armed: Option<(Instant, Waker, Timer)> = None;
+    std::future::poll_fn(move |cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            armed = None;
+            return Poll::Ready(Some(output));
+        }
+        let now = clock.now();
+        if now >= deadline {
+            armed = None;
+            return Poll::Ready(None);
+        }
+        let real_now = Instant::now();
+        match &armed {
+            Some((at, waker, _)) if *at > real_now && waker.will_wake(cx.waker()) => {
+                return Poll::Pending;
+            }
+            // The timer fired, but the clock hasn't reached the deadline.
+            Some((at, _, _)) if *at <= real_now => {
+                backoff = (backoff * 2).max(MIN_RECHECK).min(MAX_RECHECK);
+            }
+            _ => {}
+        }
+        let wait = Duration::from_nanos(deadline - now).max(backoff);
+        let at = real_now + wait;
+        let timer = wake_at(at, cx.waker().clone());
+        armed = Some((at, cx.waker().clone(), timer));
+        Poll::Pending
+    })
+    .await
+}
+
+/// Wakers waiting for the timer thread, with the ids of their [`Timer`]s
+/// and when to wake them.
+static TIMERS: Mutex<Vec<(u64, Instant, Waker)>> = Mutex::new(Vec::new());
+
+/// The id of the next [`Timer`].
+static NEXT_TIMER: AtomicU64 = AtomicU64::new(0);
+
+/// Signalled when a timer is added to [`TIMERS`].
+static TIMERS_CHANGED: Condvar = Condvar::new();
+
+static TIMER_THREAD: Once = Once::new();
+
+/// A timer set with [`wake_at`], which is cancelled when dropped so that
+/// an abandoned wait doesn't keep its waker alive until the deadline.
+struct Timer(u64);
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        TIMERS.lock().unwrap().retain(|(id, _, _)| *id != self.0);
+    }
+}
+
+/// Wake `waker` at `at`, from the timer thread, starting it if need be,
+/// unless the returned timer is dropped first.
+fn wake_at(at: Instant, waker: Waker) -> Timer {
+    TIMER_THREAD.call_once(|| {
+        std::thread::Builder::new()
+            .name("wasi-timer".to_string())
+            .spawn(run_timers)
+            .expect("failed to spawn the timer thread");
+    });
+    let id = NEXT_TIMER.fetch_add(1, Ordering::Relaxed);
+    TIMERS.lock().unwrap().push((id, at, waker));
+    TIMERS_CHANGED.notify_one();
+    Timer(id)
+}
+
+fn run_timers() {
+    let mut timers = TIMERS.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let (due, pending) = std::mem::take(&mut *timers)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, at, _)| *at <= now);
+        *timers = pending;
+        if !due.is_empty() {
+            // Wake without the lock held, in case waking polls a task that
+            // sets another timer.
+            drop(timers);
+            for (_, _, waker) in due {
+                waker.wake();
+            }
+            timers = TIMERS.lock().unwrap();
+            continue;
+        }
+        timers = match timers.iter().map(|(_, at, _)| *at).min() {
+            Some(next) => TIMERS_CHANGED.wait_timeout(timers, next - now).unwrap().0,
+            None => TIMERS_CHANGED.wait(timers).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tick_clock_advances_per_reading() {
+        let clock = TickClock::new(10);
+        let handle = clock.clone();
+        assert_eq!(clock.now(), 0);
+        assert_eq!(clock.now(), 10);
+        assert_eq!(handle.tick(), 30);
+        assert_eq!(clock.now(), 30);
+        assert_eq!(handle.peek(), 40);
+
+        let manual = TickClock::new(0);
+        assert_eq!(manual.now(), 0);
+        assert_eq!(manual.now(), 0);
+        assert_eq!(manual.tick(), 1);
+        assert_eq!(manual.now(), 1);
+    }
+
+    #[test]
+    fn posix_tz_follows_dst_transitions() {
+        let eastern = Timezone::from_posix_tz("EST5EDT,M3.2.0,M11.1.0").unwrap();
+        assert_eq!(eastern.name(), "EST");
+        assert_eq!(eastern.utc_offset(), -5 * 3600);
+
+        // Clocks sprang forward at 2023-03-12T07:00:00Z.
+        let spring = 1_678_604_400;
+        assert!(!eastern.is_daylight_saving_time(spring - 1));
+        assert_eq!(eastern.utc_offset_at(spring - 1), -5 * 3600);
+        assert_eq!(eastern.name_at(spring - 1), "EST");
+        assert!(eastern.is_daylight_saving_time(spring));
+        assert_eq!(eastern.utc_offset_at(spring), -4 * 3600);
+        assert_eq!(eastern.name_at(spring), "EDT");
+
+        // And fell back at 2023-11-05T06:00:00Z.
+        let fall = 1_699_164_000;
+        assert_eq!(eastern.utc_offset_at(fall - 1), -4 * 3600);
+        assert_eq!(eastern.utc_offset_at(fall), -5 * 3600);
+
+        // Without rules, the US ones apply.
+        let default = Timezone::from_posix_tz("EST5EDT").unwrap();
+        for when in [spring - 1, spring, fall - 1, fall] {
+            assert_eq!(default.utc_offset_at(when), eastern.utc_offset_at(when));
+        }
+    }
+
+    #[test]
+    fn posix_tz_southern_hemisphere() {
+        let sydney = Timezone::from_posix_tz("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        // Daylight saving time spans the new year.
+        assert_eq!(sydney.name_at(1_673_000_000), "AEDT");
+        // It ended at 2023-04-01T16:00:00Z and started again at
+        // 2023-09-30T16:00:00Z.
+        assert_eq!(sydney.utc_offset_at(1_680_364_799), 11 * 3600);
+        assert_eq!(sydney.utc_offset_at(1_680_364_800), 10 * 3600);
+        assert_eq!(sydney.utc_offset_at(1_696_089_599), 10 * 3600);
+        assert_eq!(sydney.utc_offset_at(1_696_089_600), 11 * 3600);
+    }
+
+    #[test]
+    fn posix_tz_fixed_offsets() {
+        let utc = Timezone::from_posix_tz("UTC0").unwrap();
+        assert_eq!(utc, Timezone::utc());
+        let india = Timezone::from_posix_tz("<+0530>-5:30").unwrap();
+        assert_eq!(india.name(), "+0530");
+        assert_eq!(india.utc_offset_at(1_700_000_000), 5 * 3600 + 30 * 60);
+        assert!(!india.is_daylight_saving_time(1_700_000_000));
+    }
+
+    #[test]
+    fn posix_tz_errors() {
+        for tz in [
+            "",
+            "EST",
+            "ES5",
+            "EST5EDT,M3.2.0",
+            "EST5EDT,M13.1.0,M11.1.0",
+            "EST5EDT,M3.2.0,M11.1.0junk",
+            "<+05-5",
+        ] {
+            assert_eq!(
+                Timezone::from_posix_tz(tz),
+                Err(Error::InvalidTimezone),
+                "{tz:?}"
+            );
+        }
+        assert_eq!(
+            Timezone::from_posix_tz(":America/New_York"),
+            Err(Error::UnknownTimezone)
+        );
+    }
+
+    #[test]
+    fn transition_dates() {
+        let day = |year, month, day| days_from_civil(year, month, day);
+        // Julian days never count February 29, zero-based ones do.
+        assert_eq!(TransitionDate::Julian(60).day(2024), day(2024, 3, 1));
+        assert_eq!(TransitionDate::Julian(60).day(2023), day(2023, 3, 1));
+        assert_eq!(TransitionDate::ZeroBased(59).day(2024), day(2024, 2, 29));
+        let last_wednesday = TransitionDate::MonthWeekDay {
+            month: 2,
+            week: 5,
+            weekday: 3,
+        };
+        assert_eq!(last_wednesday.day(2024), day(2024, 2, 28));
+    }
+
+    #[tokio::test]
+    async fn until_deadline_wakes_at_the_deadline() {
+        let clock = host::MonotonicClock::new(cap_std::ambient_authority());
+        let deadline = clock.now() + 20_000_000;
+        assert_eq!(until_deadline(async { 5 }, &clock, deadline).await, Some(5));
+        assert_eq!(
+            until_deadline(std::future::pending::<()>(), &clock, deadline).await,
+            None
+        );
+        assert!(clock.now() >= deadline);
+    }
+
+    #[test]
+    fn dropped_until_deadline_cancels_its_timer() {
+        struct Ignore;
+
+        impl std::task::Wake for Ignore {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let ignore = Arc::new(Ignore);
+        let waker = Waker::from(ignore.clone());
+        let mut cx = std::task::Context::from_waker(&waker);
+        let clock = TickClock::new(0);
+        let hour = 3_600_000_000_000;
+        let mut wait = Box::pin(until_deadline(std::future::pending::<()>(), &clock, hour));
+        assert!(wait.as_mut().poll(&mut cx).is_pending());
+        // The timer holds a clone of the waker until it is cancelled.
+        assert!(Arc::strong_count(&ignore) > 2);
+        drop(wait);
+        assert_eq!(Arc::strong_count(&ignore), 2);
+    }
+}
@@ -6,19 +6,33 @@ use cap_time_ext::{MonotonicClockExt, SystemClockExt};
 pub struct WallClock {
     /// The underlying system clock.
     clock: cap_std::time::SystemClock,
+
+    /// The platform's granularity for `clock`, queried at creation.
+    resolution: Duration,
 }
 
 impl WallClock {
     pub fn new(ambient_authority: AmbientAuthority) -> Self {
+        Self::with_resolution(
+            cap_std::time::SystemClock::new(ambient_authority),
+            platform::wall_resolution(),
+        )
+    }
+
+    /// Use `resolution` as the granularity of `clock`, or what `cap-time-ext`
+    /// reports for it if that is `None`.
+    fn with_resolution(clock: cap_std::time::SystemClock, resolution: Option<Duration>) -> Self {
+        let resolution = resolution.unwrap_or_else(|| clock.resolution());
         Self {
-            clock: cap_std::time::SystemClock::new(ambient_authority),
+            clock,
+            resolution: nonzero(resolution),
         }
     }
 }
 
 impl WasiWallClock for WallClock {
     fn resolution(&self) -> Duration {
-        self.clock.resolution()
+        self.resolution
     }
 
     fn now(&self) -> Duration {
@@ -37,19 +51,36 @@ pub struct MonotonicClock {
     /// The `Instant` this clock was created. All returned times are
     /// durations since that time.
     initial: Instant,
+
+    /// The platform's granularity for `clock`, in nanoseconds, queried at
+    /// creation.
+    resolution: u64,
 }
 
 impl MonotonicClock {
     pub fn new(ambient_authority: AmbientAuthority) -> Self {
-        let clock = cap_std::time::MonotonicClock::new(ambient_authority);
+        Self::with_resolution(
+            cap_std::time::MonotonicClock::new(ambient_authority),
+            platform::monotonic_resolution(),
+        )
+    }
+
+    /// Use `resolution` as the granularity of `clock`, or what `cap-time-ext`
+    /// reports for it if that is `None`.
+    fn with_resolution(clock: cap_std::time::MonotonicClock, resolution: Option<Duration>) -> Self {
         let initial = clock.now();
-        Self { clock, initial }
+        let resolution = resolution.unwrap_or_else(|| clock.resolution());
+        Self {
+            clock,
+            initial,
+            resolution: nonzero(resolution).as_nanos().try_into().unwrap(),
+        }
     }
 }
 
 impl WasiMonotonicClock for MonotonicClock {
     fn resolution(&self) -> u64 {
-        self.clock.resolution().as_nanos().try_into().unwrap()
+        self.resolution
     }
 
     fn now(&self) -> u64 {
@@ -64,10 +95,144 @@ impl WasiMonotonicClock for MonotonicClock {
     }
 }
 
+/// Clamp a reported resolution to at least one nanosecond, as a clock that
+/// claims to be infinitely precise is never telling the truth.
+fn nonzero(resolution: Duration) -> Duration {
+    resolution.max(Duration::from_nanos(1))
+}
+
+/// Platform queries for clock granularity.
+///
+/// Each returns `None` when the platform cannot tell, in which case the
+/// clocks fall back to what `cap-time-ext` reports.
+#[cfg(unix)]
+mod platform {
+    use cap_std::time::Duration;
+    use rustix::time::{clock_getres, ClockId};
+
+    fn getres(id: ClockId) -> Option<Duration> {
+        let res = clock_getres(id);
+        Some(Duration::new(
+            res.tv_sec.try_into().ok()?,
+            res.tv_nsec.try_into().ok()?,
+        ))
+    }
+
+    pub fn wall_resolution() -> Option<Duration> {
+        getres(ClockId::Realtime)
+    }
+
+    pub fn monotonic_resolution() -> Option<Duration> {
+        getres(ClockId::Monotonic)
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use cap_std::time::Duration;
+    use windows_sys::Win32::System::Performance::QueryPerformanceFrequency;
+
+    /// The wall clock is read with `GetSystemTimePreciseAsFileTime`, which
+    /// interpolates between clock interrupts using the performance counter
+    /// and returns a `FILETIME`, counting in units of 100 nanoseconds. It
+    /// can't resolve time more finely than either of those. The interval
+    /// between clock interrupts, as `GetSystemTimeAdjustment` reports it,
+    /// only bounds the coarse `GetSystemTimeAsFileTime`, which isn't used.
+    pub fn wall_resolution() -> Option<Duration> {
+        let filetime = Duration::from_nanos(100);
+        Some(monotonic_resolution().map_or(filetime, |counter| counter.max(filetime)))
+    }
+
+    /// The monotonic clock is read from the performance counter, which ticks
+    /// at a fixed frequency reported by the system.
+    pub fn monotonic_resolution() -> Option<Duration> {
+        let mut frequency = 0;
+        if unsafe { QueryPerformanceFrequency(&mut frequency) } == 0 || frequency <= 0 {
+            return None;
+        }
+        let frequency = u64::try_from(frequency).ok()?;
+        Some(Duration::from_nanos(
+            (1_000_000_000 + frequency - 1) / frequency,
+        ))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use cap_std::time::Duration;
+
+    pub fn wall_resolution() -> Option<Duration> {
+        None
+    }
+
+    pub fn monotonic_resolution() -> Option<Duration> {
+        None
+    }
+}
+
 pub fn clocks_ctx() -> WasiClocks {
     // Create the per-instance clock resources.
-    let monotonic = Box::new(MonotonicClock::new(ambient_authority()));
-    let wall = Box::new(WallClock::new(ambient_authority()));
+    let monotonic = MonotonicClock::new(ambient_authority());
+    let wall = WallClock::new(ambient_authority());
+
+    WasiClocks::new(wall, monotonic)
+}
 
-    WasiClocks { monotonic, wall }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wall_clock_has_subsecond_precision() {
+        let clock = WallClock::new(ambient_authority());
+        // A clock truncated to whole seconds would always report zero
+        // nanoseconds; sample a few times in case one lands exactly on a
+        // second boundary.
+        assert!((0..100).any(|_| clock.now().subsec_nanos() != 0));
+    }
+
+    #[test]
+    fn resolutions_are_nonzero() {
+        let wall = WallClock::new(ambient_authority());
+        let monotonic = MonotonicClock::new(ambient_authority());
+        assert!(wall.resolution() > Duration::ZERO);
+        assert!(monotonic.resolution() > 0);
+    }
+
+    #[test]
+    fn resolutions_are_the_ones_given_at_creation() {
+        let wall = WallClock::with_resolution(
+            SystemClock::new(ambient_authority()),
+            Some(Duration::from_micros(15)),
+        );
+        let monotonic = MonotonicClock::with_resolution(
+            cap_std::time::MonotonicClock::new(ambient_authority()),
+            Some(Duration::from_micros(15)),
+        );
+        assert_eq!(wall.resolution(), Duration::from_micros(15));
+        assert_eq!(monotonic.resolution(), 15_000);
+
+        // A resolution of zero is raised to a nanosecond.
+        let wall =
+            WallClock::with_resolution(SystemClock::new(ambient_authority()), Some(Duration::ZERO));
+        let monotonic = MonotonicClock::with_resolution(
+            cap_std::time::MonotonicClock::new(ambient_authority()),
+            Some(Duration::ZERO),
+        );
+        assert_eq!(wall.resolution(), Duration::from_nanos(1));
+        assert_eq!(monotonic.resolution(), 1);
+    }
+
+    #[test]
+    fn wall_clock_readings_differ_within_a_second() {
+        let clock = WallClock::new(ambient_authority());
+        let start = std::time::Instant::now();
+        let first = clock.now();
+        let mut later = first;
+        while later == first && start.elapsed() < std::time::Duration::from_secs(1) {
+            later = clock.now();
+        }
+        assert!(later > first, "wall clock didn't advance within a second");
+        assert!(later - first < Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,446 @@
+//! Combining and transforming input streams.
+//!
+//! The streams in this module build one input stream out of others: reading
+//! several sources one after another or taking turns between two, putting
+//! bytes back in front of a stream, or rewriting bytes as they are read.
+
+use crate::preview2::stream::{forward_input_stream, InputStream};
+use anyhow::Error;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+/// An input stream that applies a closure to the bytes read from an inner
+/// stream, for simple transforms such as XOR-decoding or case-folding.
+///
+/// The closure is called once per read with just the bytes that read filled
+/// in, and changes them in place, so it can't change how many there are.
+/// Skipped bytes are read through the closure too, so a closure that keeps
+/// state, such as its position in a key, stays in step with the stream.
+pub struct MapInputStream<T, F> {
+    inner: T,
+    map: F,
+}
+
+impl<T: InputStream, F: FnMut(&mut [u8]) + Send + Sync> MapInputStream<T, F> {
+    /// Create a stream reading `inner` and passing the bytes of each read
+    /// through `map` before the guest sees them.
+    pub fn new(inner: T, map: F) -> Self {
+        Self { inner, map }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, F> InputStream for MapInputStream<T, F>
+where
+    T: InputStream + Any,
+    F: FnMut(&mut [u8]) + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read(buf).await?;
+        (self.map)(&mut buf[..usize::try_from(n)?]);
+        Ok((n, end))
+    }
+}
+
+/// Opens the next source of a [`ConcatInputStream`].
+type StreamFactory = Box<dyn FnOnce() -> Result<Box<dyn InputStream>, Error> + Send + Sync>;
+
+/// An input stream that reads from a sequence of streams, one after another.
+///
+/// Each source is opened by a factory only once the previous source has
+/// ended, and dropped as soon as it ends, so at most one source is open at a
+/// time. This makes it suitable for presenting many large files to a guest as
+/// a single stdin. A read may return fewer bytes than are available when it
+/// reaches the end of a source; the end of the stream is reported only after
+/// the last source ends.
+#[derive(Default)]
+pub struct ConcatInputStream {
+    current: Option<Box<dyn InputStream>>,
+    pending: VecDeque<StreamFactory>,
+}
+
+impl ConcatInputStream {
+    /// Create a stream with no sources, which is immediately at its end.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a source to the end of the sequence, to be opened by `open` once
+    /// all earlier sources have ended.
+    pub fn then<S: InputStream + 'static>(
+        mut self,
+        open: impl FnOnce() -> Result<S, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.pending.push_back(Box::new(move || {
+            open().map(|s| Box::new(s) as Box<dyn InputStream>)
+        }));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for ConcatInputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        loop {
+            let current = match &mut self.current {
+                Some(current) => current,
+                None => match self.pending.pop_front() {
+                    Some(open) => self.current.insert(open()?),
+                    None => return Ok((0, true)),
+                },
+            };
+            let (n, end) = current.read(buf).await?;
+            if end {
+                self.current = None;
+                if n == 0 {
+                    continue;
+                }
+            }
+            return Ok((n, false));
+        }
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        match &self.current {
+            Some(current) => current.num_ready_bytes().await,
+            None => Ok(0),
+        }
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        match &self.current {
+            Some(current) => current.readable().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// An input stream that reads from two streams in turn, one read from each,
+/// until both have ended.
+///
+/// The rotation is strict, so the same sources always produce the same
+/// interleaving: when the source whose turn it is has no data yet, the read
+/// returns nothing rather than moving on to the other. Once one source ends,
+/// every read goes to the remaining one.
+pub struct InterleaveInputStream<A, B> {
+    first: Option<A>,
+    second: Option<B>,
+    /// Whether the next read goes to `second`.
+    second_next: bool,
+}
+
+impl<A: InputStream, B: InputStream> InterleaveInputStream<A, B> {
+    /// Create a stream whose first read goes to `first`.
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first: Some(first),
+            second: Some(second),
+            second_next: false,
+        }
+    }
+
+    /// Whether the next read goes to `second`, or `None` once both sources
+    /// have ended.
+    fn turn(&self) -> Option<bool> {
+        match (&self.first, &self.second) {
+            (Some(_), Some(_)) => Some(self.second_next),
+            (Some(_), None) => Some(false),
+            (None, Some(_)) => Some(true),
+            (None, None) => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<A: InputStream + Any, B: InputStream + Any> InputStream for InterleaveInputStream<A, B> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // The next read only goes to the source whose turn it is, so, as in
+    // `readable`, that source's readiness is this stream's.
+    #[cfg(unix)]
+    fn pollable_read(&self) -> Option<rustix::fd::BorrowedFd> {
+        match (self.turn(), &self.first, &self.second) {
+            (Some(false), Some(first), _) => first.pollable_read(),
+            (Some(true), _, Some(second)) => second.pollable_read(),
+            _ => None,
+        }
+    }
+
+    #[cfg(windows)]
+    fn pollable_read(&self) -> Option<io_extras::os::windows::BorrowedHandleOrSocket> {
+        match (self.turn(), &self.first, &self.second) {
+            (Some(false), Some(first), _) => first.pollable_read(),
+            (Some(true), _, Some(second)) => second.pollable_read(),
+            _ => None,
+        }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        loop {
+            let second = match self.turn() {
+                Some(second) => second,
+                None => return Ok((0, true)),
+            };
+            let (n, end) = match (second, &mut self.first, &mut self.second) {
+                (false, Some(first), _) => first.read(buf).await?,
+                (true, _, Some(second)) => second.read(buf).await?,
+                _ => unreachable!(),
+            };
+            if end {
+                if second {
+                    self.second = None;
+                } else {
+                    self.first = None;
+                }
+                if n == 0 {
+                    continue;
+                }
+            }
+            self.second_next = !second;
+            return Ok((n, false));
+        }
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        match (self.turn(), &self.first, &self.second) {
+            (Some(false), Some(first), _) => first.num_ready_bytes().await,
+            (Some(true), _, Some(second)) => second.num_ready_bytes().await,
+            _ => Ok(0),
+        }
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        match (self.turn(), &self.first, &self.second) {
+            (Some(false), Some(first), _) => first.readable().await,
+            (Some(true), _, Some(second)) => second.readable().await,
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An input stream that lets the host put bytes back in front of an inner
+/// stream, e.g. for a parser that has read past the end of a message.
+///
+/// Bytes pushed back are returned by the following reads before any more of
+/// the inner stream is read. They are kept until read, so they aren't lost if
+/// the stream isn't read for a while or if a read asks for fewer bytes.
+pub struct UnreadInputStream<T> {
+    inner: T,
+    /// Bytes pushed back and not yet read again.
+    pushed: Vec<u8>,
+}
+
+impl<T: InputStream> UnreadInputStream<T> {
+    /// Create a stream reading `inner`, with nothing pushed back yet.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pushed: Vec::new(),
+        }
+    }
+
+    /// Put `bytes` back in front of the stream, so that they are read next,
+    /// ahead of any bytes pushed back earlier.
+    pub fn push_back(&mut self, bytes: &[u8]) {
+        self.pushed.splice(..0, bytes.iter().copied());
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for UnreadInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[cfg(unix)]
+    fn pollable_read(&self) -> Option<rustix::fd::BorrowedFd> {
+        if self.pushed.is_empty() {
+            self.inner.pollable_read()
+        } else {
+            None
+        }
+    }
+
+    #[cfg(windows)]
+    fn pollable_read(&self) -> Option<io_extras::os::windows::BorrowedHandleOrSocket> {
+        if self.pushed.is_empty() {
+            self.inner.pollable_read()
+        } else {
+            None
+        }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        if self.pushed.is_empty() {
+            return self.inner.read(buf).await;
+        }
+        let n = buf.len().min(self.pushed.len());
+        buf[..n].copy_from_slice(&self.pushed[..n]);
+        self.pushed.drain(..n);
+        Ok((n.try_into()?, false))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(u64::try_from(self.pushed.len())? + self.inner.num_ready_bytes().await?)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        if self.pushed.is_empty() {
+            self.inner.readable().await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::pipe::{pipe, ReadPipe};
+    use crate::preview2::stream::test_util::{poll_once, Gated};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn concat_input_stream_opens_sources_lazily() {
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let mut stream = ConcatInputStream::new();
+        for name in ["one", "two", "three"] {
+            let opened = opened.clone();
+            stream = stream.then(move || {
+                opened.lock().unwrap().push(name);
+                Ok(ReadPipe::from(name))
+            });
+        }
+
+        let mut buf = [0; 16];
+        let mut contents = Vec::new();
+        loop {
+            let (n, end) = stream.read(&mut buf).await.unwrap();
+            contents.extend_from_slice(&buf[..n as usize]);
+            if end {
+                break;
+            }
+            if contents.len() <= 3 {
+                assert_eq!(*opened.lock().unwrap(), ["one"]);
+            }
+        }
+        assert_eq!(contents, b"onetwothree");
+        assert_eq!(*opened.lock().unwrap(), ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn unread_input_stream() {
+        let mut stream = UnreadInputStream::new(ReadPipe::from("world"));
+        let mut buf = [0; 3];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf, b"wor");
+        stream.push_back(b"or");
+        stream.push_back(b"w");
+        assert_eq!(stream.num_ready_bytes().await.unwrap(), 5);
+
+        let mut contents = Vec::new();
+        loop {
+            let (n, end) = stream.read(&mut buf).await.unwrap();
+            contents.extend_from_slice(&buf[..n as usize]);
+            if end {
+                break;
+            }
+        }
+        assert_eq!(contents, b"world");
+    }
+
+    #[tokio::test]
+    async fn interleave_input_stream_alternates() {
+        let mut stream = InterleaveInputStream::new(ReadPipe::from("abcdef"), ReadPipe::from("xy"));
+        let mut buf = [0; 2];
+        let mut chunks = Vec::new();
+        loop {
+            let (n, end) = stream.read(&mut buf).await.unwrap();
+            if end {
+                break;
+            }
+            chunks.push(String::from_utf8(buf[..n as usize].to_vec()).unwrap());
+        }
+        assert_eq!(chunks, ["ab", "xy", "cd", "ef"]);
+    }
+
+    #[tokio::test]
+    async fn interleave_input_stream_waits_its_turn() {
+        let (input, mut output) = pipe(4);
+        let mut stream = InterleaveInputStream::new(input, ReadPipe::from("xy"));
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+        output.write(b"a").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (1, false));
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"xy");
+        drop(output);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn dropped_interleave_read_loses_nothing() {
+        let first = Arc::new(Mutex::new(VecDeque::new()));
+        let mut stream = InterleaveInputStream::new(Gated(first.clone()), ReadPipe::from("second"));
+        let mut buf = [0; 16];
+        assert!(poll_once(&mut stream.read(&mut buf)).await.is_pending());
+
+        // The dropped read didn't use up the first source's turn.
+        first.lock().unwrap().push_back(b"first".to_vec());
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (6, false));
+        assert_eq!(&buf[..6], b"second");
+    }
+
+    #[tokio::test]
+    async fn map_input_stream_sees_only_read_bytes() {
+        let mut seen = Vec::new();
+        let mut stream = MapInputStream::new(ReadPipe::from("Hello"), move |bytes: &mut [u8]| {
+            seen.push(bytes.len());
+            assert!(seen.iter().sum::<usize>() <= 5);
+            bytes.make_ascii_uppercase();
+        });
+        let mut buf = [b'x'; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..6], b"HELLOx");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn map_input_stream_keeps_state_across_skips() {
+        let mut key = b"key".iter().copied().cycle();
+        let encoded: Vec<u8> = b"secret".iter().map(|b| b ^ key.next().unwrap()).collect();
+        let mut key = b"key".iter().copied().cycle();
+        let mut stream = MapInputStream::new(ReadPipe::from(encoded), move |bytes: &mut [u8]| {
+            for byte in bytes {
+                *byte ^= key.next().unwrap();
+            }
+        });
+        assert_eq!(stream.skip(2).await.unwrap(), (2, false));
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (4, false));
+        assert_eq!(&buf[..4], b"cret");
+    }
+}
@@ -1,11 +1,20 @@
 use crate::preview2::{
-    clocks::{self, WasiClocks},
+    clocks::{self, WasiClocks, WasiMonotonicClock},
     filesystem::{Dir, TableFsExt},
-    pipe, random, stdio,
+    pipe,
+    preview2::poll::PollStats,
+    random,
+    sched::{ReadinessScheduler, ReportAllReady},
+    stdio,
     stream::{InputStream, OutputStream, TableStreamExt},
+    wasi::clocks::{monotonic_clock::Instant, wall_clock::Datetime},
     DirPerms, FilePerms, Table,
 };
 use cap_rand::{Rng, RngCore, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 pub struct WasiCtxBuilder {
     stdin: Box<dyn InputStream>,
@@ -19,6 +28,10 @@ pub struct WasiCtxBuilder {
     insecure_random: Box<dyn RngCore + Send + Sync>,
     insecure_random_seed: u128,
     clocks: WasiClocks,
+    wall_clock_budget: Option<Duration>,
+    readiness_scheduler: Box<dyn ReadinessScheduler>,
+    timezone: Option<clocks::Timezone>,
+    on_subscribe: Option<Box<dyn Fn(u64, bool) + Send + Sync>>,
 }
 
 impl WasiCtxBuilder {
@@ -34,9 +47,9 @@ impl WasiCtxBuilder {
             cap_rand::thread_rng(cap_rand::ambient_authority()).gen::<u128>();
 
         Self {
-            stdin: Box::new(pipe::ReadPipe::new(std::io::empty())),
-            stdout: Box::new(pipe::WritePipe::new(std::io::sink())),
-            stderr: Box::new(pipe::WritePipe::new(std::io::sink())),
+            stdin: Box::new(pipe::ClosedInputStream),
+            stdout: Box::new(pipe::DiscardOutputStream),
+            stderr: Box::new(pipe::DiscardOutputStream),
             env: Vec::new(),
             args: Vec::new(),
             preopens: Vec::new(),
@@ -44,6 +57,10 @@ impl WasiCtxBuilder {
             insecure_random,
             insecure_random_seed,
             clocks: clocks::host::clocks_ctx(),
+            wall_clock_budget: None,
+            readiness_scheduler: Box::new(ReportAllReady),
+            timezone: None,
+            on_subscribe: None,
         }
     }
 
@@ -78,6 +95,19 @@ impl WasiCtxBuilder {
         self.inherit_stdin().inherit_stdout().inherit_stderr()
     }
 
+    /// Capture stdout and stderr in memory, returning handles to read what
+    /// the guest wrote once it has run.
+    pub fn with_captured_stdio(self) -> (Self, CapturedStdio) {
+        let captured = CapturedStdio {
+            stdout: pipe::WritePipe::new_in_memory(),
+            stderr: pipe::WritePipe::new_in_memory(),
+        };
+        let builder = self
+            .set_stdout(captured.stdout.clone())
+            .set_stderr(captured.stderr.clone());
+        (builder, captured)
+    }
+
     pub fn set_env(mut self, env: &[(impl AsRef<str>, impl AsRef<str>)]) -> Self {
         self.env = env
             .iter()
@@ -160,6 +190,53 @@ impl WasiCtxBuilder {
         self
     }
 
+    /// Limit how long the guest may run, in real time as measured by the
+    /// monotonic clock. The limit is enforced by the watchdog started with
+    /// [`WasiCtx::start_watchdog`].
+    pub fn wall_clock_budget(mut self, budget: Duration) -> Self {
+        self.wall_clock_budget = Some(budget);
+        self
+    }
+
+    /// Call `f` with the `when` and `absolute` arguments of every guest call
+    /// to `monotonic-clock.subscribe`, so tests can check which deadlines a
+    /// guest asked for.
+    pub fn on_subscribe(mut self, f: impl Fn(u64, bool) + Send + Sync + 'static) -> Self {
+        self.on_subscribe = Some(Box::new(f));
+        self
+    }
+
+    /// Choose which of the pollables that are ready together `poll-oneoff`
+    /// reports, e.g. to make tests of guests polling several streams
+    /// reproducible. By default, all of them are reported.
+    pub fn set_readiness_scheduler(
+        mut self,
+        readiness_scheduler: impl ReadinessScheduler + 'static,
+    ) -> Self {
+        self.readiness_scheduler = Box::new(readiness_scheduler);
+        self
+    }
+
+    /// Give the guest a timezone with a fixed offset, in seconds east of
+    /// UTC, and a display name, e.g. `3600` and `CET`.
+    ///
+    /// The timezone is pushed into the table when the context is built; the
+    /// guest has no way to ask for it, so its handle, from
+    /// [`WasiCtx::timezone`], has to be passed to the guest, e.g. in an
+    /// environment variable.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset is a day or more.
+    pub fn timezone_utc_offset(mut self, utc_offset: i32, name: impl Into<String>) -> Self {
+        assert!(
+            utc_offset.unsigned_abs() < 86_400,
+            "timezone offset must be less than a day"
+        );
+        self.timezone = Some(clocks::Timezone::new(name, utc_offset));
+        self
+    }
+
     pub fn build(self, table: &mut Table) -> Result<WasiCtx, anyhow::Error> {
         use anyhow::Context;
         let Self {
@@ -173,11 +250,18 @@ impl WasiCtxBuilder {
             insecure_random,
             insecure_random_seed,
             clocks,
+            wall_clock_budget,
+            readiness_scheduler,
+            timezone,
+            on_subscribe,
         } = self;
 
         let stdin = table.push_input_stream(stdin).context("stdin")?;
         let stdout = table.push_output_stream(stdout).context("stdout")?;
         let stderr = table.push_output_stream(stderr).context("stderr")?;
+        let timezone = timezone
+            .map(|timezone| table.push(Box::new(timezone)).context("timezone"))
+            .transpose()?;
 
         let preopens = preopens
             .into_iter()
@@ -189,6 +273,20 @@ impl WasiCtxBuilder {
             })
             .collect::<anyhow::Result<Vec<_>>>()?;
 
+        // The watchdog reads the monotonic clock from its own thread, so it
+        // needs a handle to the clock shared with the context.
+        let (clocks, wall_clock_budget) = match wall_clock_budget {
+            Some(budget) => {
+                let monotonic: Arc<dyn WasiMonotonicClock + Send + Sync> =
+                    Arc::from(clocks.monotonic);
+                let mut clocks = clocks;
+                clocks.monotonic = Box::new(SharedClock(monotonic.clone()));
+                (clocks, Some((budget, monotonic)))
+            }
+            None => (clocks, None),
+        };
+        let monotonic_baseline = (clocks.monotonic.now(), clocks.wall.now());
+
         Ok(WasiCtx {
             stdin,
             stdout,
@@ -200,15 +298,68 @@ impl WasiCtxBuilder {
             insecure_random,
             insecure_random_seed,
             clocks,
+            wall_clock_budget,
+            monotonic_baseline,
+            readiness_scheduler,
+            pollable_labels: HashMap::new(),
+            poll_stats: PollStats::default(),
+            timezone,
+            on_subscribe,
         })
     }
 }
 
+/// Handles to the output captured by
+/// [`WasiCtxBuilder::with_captured_stdio`].
+///
+/// The buffers are shared with the streams installed in the `WasiCtx`, so
+/// the contents can be read while the guest is still running as well as
+/// after it has finished.
+#[derive(Clone)]
+pub struct CapturedStdio {
+    stdout: pipe::WritePipe<std::io::Cursor<Vec<u8>>>,
+    stderr: pipe::WritePipe<std::io::Cursor<Vec<u8>>>,
+}
+
+impl CapturedStdio {
+    /// Everything the guest has written to stdout so far.
+    pub fn stdout_contents(&self) -> Vec<u8> {
+        self.stdout.contents()
+    }
+
+    /// Everything the guest has written to stderr so far.
+    pub fn stderr_contents(&self) -> Vec<u8> {
+        self.stderr.contents()
+    }
+}
+
 pub trait WasiView: Send {
     fn table(&self) -> &Table;
     fn table_mut(&mut self) -> &mut Table;
     fn ctx(&self) -> &WasiCtx;
     fn ctx_mut(&mut self) -> &mut WasiCtx;
+
+    /// Pick the output stream that a guest's write to `stream` goes to.
+    ///
+    /// This is consulted on every write and poll of an output stream, so an
+    /// embedding can route a guest's output dynamically, for example sending
+    /// stdout to a different stream in the table for each request handled by
+    /// a store shared between logical sessions. The default writes to
+    /// `stream` itself.
+    fn resolve_output_stream(&self, stream: u32) -> u32 {
+        stream
+    }
+
+    /// Account for `bytes` moved by a guest's stream read, skip or write.
+    ///
+    /// Host I/O consumes no fuel, so without this a guest can move any amount
+    /// of data while staying within its fuel limit. An embedding that meters
+    /// guests can charge for I/O here, e.g. against a budget kept next to its
+    /// `WasiCtx`; returning an error traps the guest. The default does
+    /// nothing.
+    fn charge_io(&mut self, _bytes: u64) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct WasiCtx {
@@ -222,4 +373,351 @@ pub struct WasiCtx {
     pub(crate) stdin: u32,
     pub(crate) stdout: u32,
     pub(crate) stderr: u32,
+    pub(crate) readiness_scheduler: Box<dyn ReadinessScheduler>,
+    pub(crate) pollable_labels: HashMap<u32, String>,
+    pub(crate) poll_stats: PollStats,
+    pub(crate) on_subscribe: Option<Box<dyn Fn(u64, bool) + Send + Sync>>,
+    timezone: Option<u32>,
+    wall_clock_budget: Option<(Duration, Arc<dyn WasiMonotonicClock + Send + Sync>)>,
+    monotonic_baseline: (u64, Duration),
+}
+
+impl WasiCtx {
+    /// A reading of this context's monotonic clock paired with a reading of
+    /// its wall clock taken at the same moment.
+    ///
+    /// This relates the instants a guest sees on the monotonic clock to wall
+    /// clock time, e.g. for logging: a guest's instant `t` happened about
+    /// `t - instant` nanoseconds after the returned datetime. The pair is
+    /// captured once, when the context is built, so the translation drifts
+    /// from the wall clock over long runs as the two clocks run at slightly
+    /// different rates or the wall clock is adjusted.
+    pub fn monotonic_baseline(&self) -> (Instant, Datetime) {
+        let (instant, wall) = self.monotonic_baseline;
+        let datetime = Datetime {
+            seconds: wall.as_secs(),
+            nanoseconds: wall.subsec_nanos(),
+        };
+        (instant, datetime)
+    }
+
+    /// Attach a label to the pollable at index `pollable` in the table, e.g.
+    /// the name of the connection whose stream it waits on, as found with
+    /// [`TablePollableExt::debug_pollables`](crate::preview2::TablePollableExt::debug_pollables).
+    ///
+    /// When `poll_oneoff` reports the pollable ready, the label is included
+    /// in the `tracing` event recorded for it, to show which logical resource
+    /// woke the guest. The label is forgotten when the guest drops the
+    /// pollable.
+    pub fn label_pollable(&mut self, pollable: u32, label: impl Into<String>) {
+        self.pollable_labels.insert(pollable, label.into());
+    }
+
+    /// The handle of the timezone set with
+    /// [`WasiCtxBuilder::timezone_utc_offset`], if any.
+    pub fn timezone(&self) -> Option<u32> {
+        self.timezone
+    }
+
+    /// How many pollables the guest has subscribed to and how many were
+    /// reported ready, over all of its calls to `poll_oneoff` so far.
+    pub fn poll_stats(&self) -> PollStats {
+        self.poll_stats
+    }
+
+    /// Shut down every output stream in `table`, e.g. to flush buffered
+    /// output once the guest has finished, giving up on the streams that are
+    /// not done within `timeout` on this context's monotonic clock.
+    ///
+    /// This keeps a stuck consumer downstream of a stream from hanging the
+    /// host. A stream that blocks its thread while shutting down, rather than
+    /// returning a pending future, can't be given up on. The streams are left
+    /// in the table; shutting one down again, e.g. when the guest drops it,
+    /// must be harmless.
+    pub async fn shutdown(&self, table: &mut Table, timeout: Duration) -> ShutdownReport {
+        let clock = &*self.clocks.monotonic;
+        let deadline = clock
+            .now()
+            .saturating_add(timeout.as_nanos().try_into().unwrap_or(u64::MAX));
+        let streams: Vec<u32> = table
+            .iter_of::<Box<dyn OutputStream>>()
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut report = ShutdownReport::default();
+        for index in streams {
+            let stream = match table.get_output_stream_mut(index) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            match clocks::until_deadline(stream.shutdown(), clock, deadline).await {
+                Some(Ok(())) => report.drained.push(index),
+                Some(Err(err)) => {
+                    tracing::warn!("failed to shut down output stream {index}: {err:?}");
+                    report.failed.push((index, err));
+                }
+                None => {
+                    tracing::warn!(
+                        "gave up shutting down output stream {index}; \
+                         its buffered output may be lost"
+                    );
+                    report.timed_out.push(index);
+                }
+            }
+        }
+        report
+    }
+
+    /// Start enforcing the [`WasiCtxBuilder::wall_clock_budget`], counting
+    /// from now, or return `None` if no budget was set.
+    ///
+    /// Once the budget has passed, the watchdog increments `engine`'s epoch
+    /// from a background thread. A guest in a store with epoch interruption
+    /// enabled (see `Config::epoch_interruption`) and a deadline of one tick
+    /// (`Store::set_epoch_deadline(1)`) then traps at its next epoch check;
+    /// a guest waiting in a host call traps when the call returns. Unlike
+    /// fuel, which counts instructions, this also bounds guests that spend
+    /// their time waiting on I/O. The epoch is shared by every store using
+    /// `engine`, so those stores are interrupted as well.
+    ///
+    /// Dropping the returned [`Watchdog`] stops it.
+    pub fn start_watchdog(&self, engine: &wasmtime::Engine) -> Option<Watchdog> {
+        let (budget, clock) = self.wall_clock_budget.clone()?;
+        let deadline = clock
+            .now()
+            .saturating_add(budget.as_nanos().try_into().unwrap_or(u64::MAX));
+        let state = Arc::new(WatchdogState {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            fired: AtomicBool::new(false),
+        });
+        let engine = engine.clone();
+        let thread = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                let mut stopped = state.stopped.lock().unwrap();
+                while !*stopped {
+                    let now = clock.now();
+                    if now >= deadline {
+                        tracing::debug!("guest exceeded its wall-clock budget, interrupting");
+                        state.fired.store(true, Ordering::SeqCst);
+                        engine.increment_epoch();
+                        return;
+                    }
+                    let wait = Duration::from_nanos(deadline - now).min(WATCHDOG_POLL_INTERVAL);
+                    stopped = state.wake.wait_timeout(stopped, wait).unwrap().0;
+                }
+            }
+        });
+        Some(Watchdog {
+            state,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// The longest the watchdog waits between readings of the clock, so a clock
+/// that doesn't keep to real time, such as a test clock, is still noticed
+/// soon after it passes the deadline.
+const WATCHDOG_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A monotonic clock shared between a `WasiCtx` and its watchdog.
+struct SharedClock(Arc<dyn WasiMonotonicClock + Send + Sync>);
+
+impl WasiMonotonicClock for SharedClock {
+    fn resolution(&self) -> u64 {
+        self.0.resolution()
+    }
+
+    fn now(&self) -> u64 {
+        self.0.now()
+    }
+}
+
+/// The watchdog started by [`WasiCtx::start_watchdog`].
+pub struct Watchdog {
+    state: Arc<WatchdogState>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+struct WatchdogState {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    fired: AtomicBool,
+}
+
+impl Watchdog {
+    /// Whether the budget ran out and the engine was interrupted, e.g. to
+    /// tell a trap caused by the watchdog apart from one raised by the guest.
+    pub fn fired(&self) -> bool {
+        self.state.fired.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        *self.state.stopped.lock().unwrap() = true;
+        self.state.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The outcome of [`WasiCtx::shutdown`], listing output streams by their
+/// indices in the table.
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// The streams that shut down successfully.
+    pub drained: Vec<u32>,
+    /// The streams that failed to shut down, with their errors.
+    pub failed: Vec<(u32, anyhow::Error)>,
+    /// The streams still shutting down when the timeout passed.
+    pub timed_out: Vec<u32>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::{host::WallClock, TickClock};
+    use std::any::Any;
+
+    /// An output stream whose consumer never takes its last bytes.
+    struct Stuck;
+
+    #[async_trait::async_trait]
+    impl OutputStream for Stuck {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        async fn shutdown(&mut self) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+
+        async fn writable(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_on_stuck_streams() {
+        let mut table = Table::new();
+        let stderr = pipe::WritePipe::new_in_memory();
+        let ctx = WasiCtxBuilder::new()
+            .set_stdout(Stuck)
+            .set_stderr(stderr.clone())
+            .set_clocks(WasiClocks::new(
+                WallClock::new(cap_std::ambient_authority()),
+                TickClock::new(10_000),
+            ))
+            .build(&mut table)
+            .unwrap();
+
+        let report = ctx.shutdown(&mut table, Duration::from_micros(10)).await;
+        assert_eq!(report.drained, [ctx.stderr]);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.timed_out, [ctx.stdout]);
+    }
+
+    /// A monotonic clock that reads zero until the test hands it a channel,
+    /// and then takes each reading from the channel.
+    #[derive(Clone, Default)]
+    struct HandedClock(Arc<Mutex<Option<std::sync::mpsc::Receiver<u64>>>>);
+
+    impl WasiMonotonicClock for HandedClock {
+        fn resolution(&self) -> u64 {
+            1
+        }
+
+        fn now(&self) -> u64 {
+            match &*self.0.lock().unwrap() {
+                Some(readings) => readings.recv().unwrap(),
+                None => 0,
+            }
+        }
+    }
+
+    #[test]
+    fn watchdog_interrupts_once_budget_passes() {
+        let mut table = Table::new();
+        let clock = HandedClock::default();
+        let ctx = WasiCtxBuilder::new()
+            .set_clocks(WasiClocks::new(
+                WallClock::new(cap_std::ambient_authority()),
+                clock.clone(),
+            ))
+            .wall_clock_budget(Duration::from_nanos(3))
+            .build(&mut table)
+            .unwrap();
+        let engine =
+            wasmtime::Engine::new(wasmtime::Config::new().epoch_interruption(true)).unwrap();
+
+        let mut watchdog = ctx.start_watchdog(&engine).unwrap();
+        // The channel has no buffer, so a send only returns once the watchdog
+        // is taking a reading, i.e. once it has acted on the previous one.
+        let (readings, receiver) = std::sync::mpsc::sync_channel(0);
+        *clock.0.lock().unwrap() = Some(receiver);
+        readings.send(2).unwrap();
+        readings.send(2).unwrap();
+        assert!(!watchdog.fired());
+
+        readings.send(3).unwrap();
+        // The watchdog's thread only finishes on its own once it has fired.
+        watchdog.thread.take().unwrap().join().unwrap();
+        assert!(watchdog.fired());
+    }
+
+    #[test]
+    fn no_watchdog_without_budget() {
+        let mut table = Table::new();
+        let ctx = WasiCtxBuilder::new().build(&mut table).unwrap();
+        let engine = wasmtime::Engine::default();
+        assert!(ctx.start_watchdog(&engine).is_none());
+    }
+
+    #[test]
+    fn monotonic_baseline_is_captured_at_build() {
+        struct FixedWall;
+
+        impl clocks::WasiWallClock for FixedWall {
+            fn resolution(&self) -> Duration {
+                Duration::from_nanos(1)
+            }
+            fn now(&self) -> Duration {
+                Duration::new(1_700_000_000, 5)
+            }
+        }
+
+        let mut table = Table::new();
+        let clock = TickClock::new(0);
+        clock.tick();
+        let ctx = WasiCtxBuilder::new()
+            .set_clocks(WasiClocks::new(FixedWall, clock.clone()))
+            .build(&mut table)
+            .unwrap();
+        clock.tick();
+
+        let (instant, datetime) = ctx.monotonic_baseline();
+        assert_eq!(instant, 1);
+        assert_eq!(datetime.seconds, 1_700_000_000);
+        assert_eq!(datetime.nanoseconds, 5);
+    }
+
+    #[test]
+    fn timezone_is_pushed_into_the_table() {
+        let mut table = Table::new();
+        let ctx = WasiCtxBuilder::new().build(&mut table).unwrap();
+        assert_eq!(ctx.timezone(), None);
+
+        let ctx = WasiCtxBuilder::new()
+            .timezone_utc_offset(3600, "CET")
+            .build(&mut table)
+            .unwrap();
+        let timezone = table
+            .get::<clocks::Timezone>(ctx.timezone().unwrap())
+            .unwrap();
+        assert_eq!(timezone.name(), "CET");
+        assert_eq!(timezone.utc_offset(), 3600);
+    }
 }
@@ -203,6 +203,16 @@ impl OutputStream for FileOutputStream {
         self.file.is_write_vectored_at()
     }
 
+    /// Wait for the file's data to reach storage.
+    async fn sync_data(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Wait for the file's data and metadata to reach storage.
+    async fn sync_all(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
     /// Test whether this stream is writable.
     async fn writable(&self) -> anyhow::Result<()> {
         // FIXME perm check?
@@ -261,6 +271,16 @@ impl OutputStream for FileAppendStream {
         self.file.is_write_vectored_at()
     }
 
+    /// Wait for the file's data to reach storage.
+    async fn sync_data(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Wait for the file's data and metadata to reach storage.
+    async fn sync_all(&mut self) -> anyhow::Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
     /// Test whether this stream is writable.
     async fn writable(&self) -> anyhow::Result<()> {
         // FIXME perm check?
@@ -0,0 +1,811 @@
+//! Stream instrumentation.
+//!
+//! The wrappers in this module forward every operation to an inner stream
+//! while recording how many bytes each read or write moved and how long it
+//! took, which helps tell whether a guest is bound on host I/O. They also keep
+//! the last error from the inner stream for post-mortem debugging.
+//! Recording can be switched off with [`StreamMetrics::set_recording`],
+//! leaving the wrappers close to free.
+//! With the `hashing` feature, `HashingOutputStream` instead digests the
+//! bytes written, for integrity checks.
+
+use crate::preview2::clocks::WasiMonotonicClock;
+use crate::preview2::stream::{
+    forward_input_stream, forward_output_stream, InputStream, OutputStream,
+};
+use anyhow::Error;
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A point-in-time copy of the counters kept by [`StreamMetrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// The number of reads or writes.
+    pub calls: u64,
+    /// The total number of bytes read or written.
+    pub bytes: u64,
+    /// The largest number of bytes moved by a single call.
+    pub max_bytes: u64,
+    /// The total time spent in reads or writes.
+    pub time: Duration,
+    /// The longest time spent in a single read or write.
+    pub max_time: Duration,
+    /// The number of waits for readiness.
+    pub waits: u64,
+    /// The total time spent waiting for readiness.
+    pub wait_time: Duration,
+    /// The longest single wait for readiness.
+    pub max_wait_time: Duration,
+}
+
+/// Counters shared between an instrumented stream and its observers.
+///
+/// Clones observe the same counters, so a handle can be kept after the stream
+/// itself has been handed to a `WasiCtx`.
+#[derive(Debug, Clone, Default)]
+pub struct StreamMetrics(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    paused: AtomicBool,
+    snapshot: Mutex<MetricsSnapshot>,
+}
+
+impl StreamMetrics {
+    /// Copy out the current counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        *self.0.snapshot.lock().unwrap()
+    }
+
+    /// Stop or resume recording. Recording starts out on.
+    ///
+    /// While recording is off, the instrumented streams check this once per
+    /// call and otherwise only forward it, without reading the clock or
+    /// taking the counters' lock, so a wrapper can be left in place and
+    /// sampled only when needed.
+    pub fn set_recording(&self, recording: bool) {
+        self.0.paused.store(!recording, Ordering::Relaxed);
+    }
+
+    /// When a call being recorded started, or `None` if recording is off.
+    fn start(&self) -> Option<Instant> {
+        if self.0.paused.load(Ordering::Relaxed) {
+            None
+        } else {
+            Some(Instant::now())
+        }
+    }
+
+    fn record_call(&self, start: Option<Instant>, bytes: u64) {
+        let Some(start) = start else { return };
+        let elapsed = start.elapsed();
+        let mut m = self.0.snapshot.lock().unwrap();
+        m.calls += 1;
+        m.bytes += bytes;
+        m.max_bytes = m.max_bytes.max(bytes);
+        m.time += elapsed;
+        m.max_time = m.max_time.max(elapsed);
+    }
+
+    fn record_wait(&self, start: Option<Instant>) {
+        let Some(start) = start else { return };
+        let elapsed = start.elapsed();
+        let mut m = self.0.snapshot.lock().unwrap();
+        m.waits += 1;
+        m.wait_time += elapsed;
+        m.max_wait_time = m.max_wait_time.max(elapsed);
+    }
+}
+
+/// Remember a copy of the error in `result`, if any, in `last_error`.
+///
+/// `anyhow::Error` can't be cloned, so the copy keeps only the message,
+/// including the messages of any underlying causes.
+fn keep_error<T>(last_error: &mut Option<Error>, result: Result<T, Error>) -> Result<T, Error> {
+    if let Err(err) = &result {
+        *last_error = Some(anyhow::anyhow!("{err:#}"));
+    }
+    result
+}
+
+/// An input stream that records metrics for an inner stream.
+pub struct InstrumentedInputStream<T> {
+    inner: T,
+    metrics: StreamMetrics,
+    last_error: Option<Error>,
+}
+
+impl<T: InputStream> InstrumentedInputStream<T> {
+    /// Create a stream recording the reads of `inner` into a fresh set of
+    /// counters, which [`metrics`](Self::metrics) hands out.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            metrics: StreamMetrics::default(),
+            last_error: None,
+        }
+    }
+
+    /// A handle to this stream's metrics.
+    pub fn metrics(&self) -> StreamMetrics {
+        self.metrics.clone()
+    }
+
+    /// The most recent error returned by the inner stream, kept for
+    /// diagnostics after the guest has moved on.
+    ///
+    /// This is a copy of the original error's message; the original is
+    /// passed on to the guest.
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for InstrumentedInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let start = self.metrics.start();
+        let (n, end) = keep_error(&mut self.last_error, self.inner.read(buf).await)?;
+        self.metrics.record_call(start, n);
+        Ok((n, end))
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        bufs: &mut [std::io::IoSliceMut<'a>],
+    ) -> Result<(u64, bool), Error> {
+        let start = self.metrics.start();
+        let (n, end) = keep_error(&mut self.last_error, self.inner.read_vectored(bufs).await)?;
+        self.metrics.record_call(start, n);
+        Ok((n, end))
+    }
+
+    fn is_read_vectored(&self) -> bool {
+        self.inner.is_read_vectored()
+    }
+
+    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
+        let start = self.metrics.start();
+        let (n, end) = keep_error(&mut self.last_error, self.inner.skip(nelem).await)?;
+        self.metrics.record_call(start, n);
+        Ok((n, end))
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        let start = self.metrics.start();
+        let result = self.inner.readable().await;
+        self.metrics.record_wait(start);
+        result
+    }
+}
+
+/// An output stream that records metrics for an inner stream.
+pub struct InstrumentedOutputStream<T> {
+    inner: T,
+    metrics: StreamMetrics,
+    last_error: Option<Error>,
+}
+
+impl<T: OutputStream> InstrumentedOutputStream<T> {
+    /// Create a stream recording the writes of `inner` into a fresh set of
+    /// counters, which [`metrics`](Self::metrics) hands out.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            metrics: StreamMetrics::default(),
+            last_error: None,
+        }
+    }
+
+    /// A handle to this stream's metrics.
+    pub fn metrics(&self) -> StreamMetrics {
+        self.metrics.clone()
+    }
+
+    /// The most recent error returned by the inner stream, kept for
+    /// diagnostics after the guest has moved on.
+    ///
+    /// This is a copy of the original error's message; the original is
+    /// passed on to the guest.
+    pub fn last_error(&self) -> Option<&Error> {
+        self.last_error.as_ref()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for InstrumentedOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let start = self.metrics.start();
+        let n = keep_error(&mut self.last_error, self.inner.write(buf).await)?;
+        self.metrics.record_call(start, n);
+        Ok(n)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let start = self.metrics.start();
+        let n = keep_error(&mut self.last_error, self.inner.write_vectored(bufs).await)?;
+        self.metrics.record_call(start, n);
+        Ok(n)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    async fn splice(
+        &mut self,
+        src: &mut dyn InputStream,
+        nelem: u64,
+    ) -> Result<(u64, bool), Error> {
+        let start = self.metrics.start();
+        let (n, end) = keep_error(&mut self.last_error, self.inner.splice(src, nelem).await)?;
+        self.metrics.record_call(start, n);
+        Ok((n, end))
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        let start = self.metrics.start();
+        let n = keep_error(&mut self.last_error, self.inner.write_zeroes(nelem).await)?;
+        self.metrics.record_call(start, n);
+        Ok(n)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        keep_error(&mut self.last_error, self.inner.shutdown().await)
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        keep_error(&mut self.last_error, self.inner.sync_data().await)
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        keep_error(&mut self.last_error, self.inner.sync_all().await)
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        let start = self.metrics.start();
+        let result = self.inner.writable().await;
+        self.metrics.record_wait(start);
+        result
+    }
+}
+
+/// A moving estimate of a stream's throughput.
+struct RateState {
+    clock: Box<dyn WasiMonotonicClock>,
+    /// The averaging window, in nanoseconds.
+    window: f64,
+    /// When the estimate was last updated.
+    last: u64,
+    /// Bytes moved since `last`.
+    pending: u64,
+    /// The estimate, in bytes per second.
+    rate: f64,
+}
+
+impl RateState {
+    /// Fold the bytes moved since the last update into the estimate.
+    ///
+    /// This is an exponentially weighted moving average, weighted by the time
+    /// between updates, so the estimate also decays while the stream is idle.
+    fn update(&mut self) -> f64 {
+        let now = self.clock.now();
+        if now > self.last {
+            let elapsed = (now - self.last) as f64;
+            let current = self.pending as f64 * 1e9 / elapsed;
+            let weight = 1.0 - (-elapsed / self.window).exp();
+            self.rate += weight * (current - self.rate);
+            self.last = now;
+            self.pending = 0;
+        }
+        self.rate
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.pending += bytes;
+        self.update();
+    }
+}
+
+/// A handle to the throughput estimate of a [`RateSampledStream`].
+///
+/// Clones observe the same estimate, so a handle can be kept after the
+/// stream itself has been handed to a `WasiCtx`.
+#[derive(Clone)]
+pub struct FlowRate(Arc<Mutex<RateState>>);
+
+impl FlowRate {
+    /// The current throughput estimate, in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.0.lock().unwrap().update()
+    }
+}
+
+/// A stream wrapper that estimates the current throughput of an inner input
+/// or output stream.
+///
+/// Unlike the totals kept by the instrumented streams, the estimate follows
+/// changes in the rate: it is an average over roughly the last `window` of
+/// time, measured on the given clock, and falls toward zero while the
+/// stream is idle.
+pub struct RateSampledStream<T> {
+    inner: T,
+    rate: FlowRate,
+}
+
+impl<T> RateSampledStream<T> {
+    /// Create a stream estimating the throughput of `inner`, averaged over
+    /// about the last `window` of time as measured on `clock`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is zero.
+    pub fn new(inner: T, clock: impl WasiMonotonicClock + 'static, window: Duration) -> Self {
+        assert!(!window.is_zero(), "window must be nonzero");
+        let last = clock.now();
+        Self {
+            inner,
+            rate: FlowRate(Arc::new(Mutex::new(RateState {
+                clock: Box::new(clock),
+                window: window.as_nanos() as f64,
+                last,
+                pending: 0,
+                rate: 0.0,
+            }))),
+        }
+    }
+
+    /// The current throughput estimate, in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate.rate()
+    }
+
+    /// A handle to this stream's throughput estimate.
+    pub fn flow_rate(&self) -> FlowRate {
+        self.rate.clone()
+    }
+
+    fn record(&self, bytes: u64) {
+        self.rate.0.lock().unwrap().record(bytes);
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for RateSampledStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read(buf).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        bufs: &mut [std::io::IoSliceMut<'a>],
+    ) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read_vectored(bufs).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    fn is_read_vectored(&self) -> bool {
+        self.inner.is_read_vectored()
+    }
+
+    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.skip(nelem).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for RateSampledStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        self.record(n);
+        Ok(n)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let n = self.inner.write_vectored(bufs).await?;
+        self.record(n);
+        Ok(n)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    async fn splice(
+        &mut self,
+        src: &mut dyn InputStream,
+        nelem: u64,
+    ) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.splice(src, nelem).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        let n = self.inner.write_zeroes(nelem).await?;
+        self.record(n);
+        Ok(n)
+    }
+}
+
+/// A stream wrapper that measures the time from its creation until the first
+/// byte is read from or written to an inner stream.
+///
+/// This shows how long a guest takes to start producing or consuming data,
+/// e.g. to tell slow guest startup apart from slow I/O.
+pub struct TimeToFirstByteStream<T> {
+    inner: T,
+    clock: Box<dyn WasiMonotonicClock>,
+    /// When this stream was created, on `clock`.
+    created: u64,
+    first_byte: Option<Duration>,
+}
+
+impl<T> TimeToFirstByteStream<T> {
+    /// Create a stream measuring the time to the first byte of `inner` on
+    /// `clock`, starting now.
+    pub fn new(inner: T, clock: impl WasiMonotonicClock + 'static) -> Self {
+        let created = clock.now();
+        Self {
+            inner,
+            clock: Box::new(clock),
+            created,
+            first_byte: None,
+        }
+    }
+
+    /// The time from creation to the first byte moved, or `None` if no bytes
+    /// have been moved yet.
+    pub fn ttfb(&self) -> Option<Duration> {
+        self.first_byte
+    }
+
+    fn record(&mut self, bytes: u64) {
+        if bytes != 0 && self.first_byte.is_none() {
+            let elapsed = self.clock.now().saturating_sub(self.created);
+            self.first_byte = Some(Duration::from_nanos(elapsed));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for TimeToFirstByteStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read(buf).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        bufs: &mut [std::io::IoSliceMut<'a>],
+    ) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read_vectored(bufs).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    fn is_read_vectored(&self) -> bool {
+        self.inner.is_read_vectored()
+    }
+
+    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.skip(nelem).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for TimeToFirstByteStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        self.record(n);
+        Ok(n)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[std::io::IoSlice<'a>]) -> Result<u64, Error> {
+        let n = self.inner.write_vectored(bufs).await?;
+        self.record(n);
+        Ok(n)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    async fn splice(
+        &mut self,
+        src: &mut dyn InputStream,
+        nelem: u64,
+    ) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.splice(src, nelem).await?;
+        self.record(n);
+        Ok((n, end))
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        let n = self.inner.write_zeroes(nelem).await?;
+        self.record(n);
+        Ok(n)
+    }
+}
+
+/// An output stream wrapper that feeds every byte written to an inner stream
+/// into a hasher, such as SHA-256 from the `sha2` crate.
+///
+/// Only the bytes the inner stream accepts are hashed, so after the guest is
+/// done, [`finalize`](Self::finalize) gives the digest of exactly what it
+/// produced without a second pass over the data. The stream can be taken back
+/// out of the table with `Table::delete` to finalize it.
+///
+/// Only available with the `hashing` feature.
+#[cfg(feature = "hashing")]
+pub struct HashingOutputStream<T, H> {
+    inner: T,
+    hasher: H,
+}
+
+#[cfg(feature = "hashing")]
+impl<T, H: digest::Digest> HashingOutputStream<T, H> {
+    /// Create a stream writing to `inner`, with a fresh `H` hasher.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: H::new(),
+        }
+    }
+
+    /// Consume this stream and return the digest of everything written to
+    /// it.
+    pub fn finalize(self) -> digest::Output<H> {
+        self.hasher.finalize()
+    }
+}
+
+#[cfg(feature = "hashing")]
+#[async_trait::async_trait]
+impl<T, H> OutputStream for HashingOutputStream<T, H>
+where
+    T: OutputStream + Any,
+    H: digest::Digest + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        self.hasher.update(&buf[..usize::try_from(n)?]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+    use crate::preview2::pipe::{ReadPipe, WritePipe};
+
+    #[tokio::test]
+    async fn counts_bytes() {
+        let mut input = InstrumentedInputStream::new(ReadPipe::from("hello, world"));
+        let mut buf = [0; 5];
+        input.read(&mut buf).await.unwrap();
+        input.read(&mut buf).await.unwrap();
+        input.readable().await.unwrap();
+        let snapshot = input.metrics().snapshot();
+        assert_eq!(snapshot.calls, 2);
+        assert_eq!(snapshot.bytes, 10);
+        assert_eq!(snapshot.max_bytes, 5);
+        assert_eq!(snapshot.waits, 1);
+
+        let mut output = InstrumentedOutputStream::new(WritePipe::new(std::io::sink()));
+        let metrics = output.metrics();
+        output.write(b"abc").await.unwrap();
+        output.write_zeroes(7).await.unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.calls, 2);
+        assert_eq!(snapshot.bytes, 10);
+        assert_eq!(snapshot.max_bytes, 7);
+    }
+
+    #[tokio::test]
+    async fn records_only_while_recording() {
+        let mut output = InstrumentedOutputStream::new(WritePipe::new(std::io::sink()));
+        let metrics = output.metrics();
+        metrics.set_recording(false);
+        output.write(b"unseen").await.unwrap();
+        output.writable().await.unwrap();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+
+        metrics.set_recording(true);
+        output.write(b"seen").await.unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.calls, 1);
+        assert_eq!(snapshot.bytes, 4);
+        assert_eq!(snapshot.waits, 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_last_error() {
+        let (input, output) = crate::preview2::pipe::pipe(4);
+        let mut output = InstrumentedOutputStream::new(output);
+        output.write(b"ok").await.unwrap();
+        assert!(output.last_error().is_none());
+        drop(input);
+        assert!(output.write(b"lost").await.is_err());
+        assert_eq!(output.last_error().unwrap().to_string(), "pipe closed");
+    }
+
+    #[tokio::test]
+    async fn rate_follows_throughput() {
+        let clock = TickClock::new(0);
+        let mut output = RateSampledStream::new(
+            WritePipe::new(std::io::sink()),
+            clock.clone(),
+            Duration::from_millis(100),
+        );
+        let rate = output.flow_rate();
+
+        // 1000 bytes every 10ms is 100,000 bytes per second.
+        for i in 1..=300 {
+            clock.set(i * 10_000_000);
+            output.write(&[0; 1000]).await.unwrap();
+        }
+        assert!((rate.rate() - 100_000.0).abs() < 1.0);
+
+        // After ten windows without any writes, the rate has all but vanished.
+        clock.set(4_000_000_000);
+        assert!(output.rate() < 10.0);
+    }
+
+    #[tokio::test]
+    async fn time_to_first_byte() {
+        let clock = TickClock::new(0);
+        clock.set(1_000);
+        let (input, mut output) = crate::preview2::pipe::pipe(4);
+        let mut input = TimeToFirstByteStream::new(input, clock.clone());
+        let mut buf = [0; 16];
+
+        // Reads that find no data yet don't count.
+        clock.set(3_000);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, false));
+        assert_eq!(input.ttfb(), None);
+
+        output.write(b"hello").await.unwrap();
+        clock.set(5_000);
+        input.read(&mut buf).await.unwrap();
+        clock.set(9_000);
+        input.read(&mut buf).await.unwrap();
+        assert_eq!(input.ttfb(), Some(Duration::from_nanos(4_000)));
+    }
+
+    #[cfg(feature = "hashing")]
+    #[tokio::test]
+    async fn hashes_accepted_bytes() {
+        use sha2::{Digest, Sha256};
+
+        let (mut input, output) = crate::preview2::pipe::byte_bounded_pipe(8);
+        let mut output = HashingOutputStream::<_, Sha256>::new(output);
+        // The pipe only takes the first 8 bytes, and only those are hashed.
+        assert_eq!(output.write(b"hello, world").await.unwrap(), 8);
+        let mut buf = [0; 8];
+        input.read(&mut buf).await.unwrap();
+        assert_eq!(output.write(b"orld").await.unwrap(), 4);
+        assert_eq!(output.finalize(), Sha256::digest(b"hello, world"));
+    }
+}
@@ -18,27 +18,50 @@
 //! root of the wasmtime-wasi crate, and move its other exports underneath a
 //! `pub mod legacy` with an off-by-default feature flag, and after 2
 //! releases, retire and remove that code from our tree.
+//!
+//! This implementation does not depend on any particular async runtime. The
+//! streams, pipes and clocks here never spawn async tasks or use runtime
+//! timers, so the futures of the host traits can be driven by any executor,
+//! including a simple `block_on`. Waiting in `poll-oneoff` blocks the calling
+//! thread in the host's `poll` rather than awaiting a timer. Plain host
+//! threads are used where something has to happen in the background: waits
+//! that give up at a deadline, such as [`WasiCtx::shutdown`], are woken by a
+//! thread shared by all of them, and [`WasiCtx::start_watchdog`] spawns a
+//! thread of its own.
 
+#[cfg(feature = "futures")]
+pub mod adapters;
+pub mod capture;
+pub mod checked;
 pub mod clocks;
+pub mod combine;
 mod ctx;
 mod error;
 pub(crate) mod filesystem;
+pub mod metrics;
 pub mod pipe;
 #[cfg(feature = "preview1-on-preview2")]
 pub mod preview1;
 pub mod preview2;
 pub mod random;
 mod sched;
+pub mod shaping;
 pub mod stdio;
 pub mod stream;
 pub mod table;
+pub mod tee;
+pub mod testing;
+pub mod text;
+pub mod timing;
 pub mod wasi;
 
 pub use cap_fs_ext::SystemTimeSpec;
 pub use cap_rand::RngCore;
 pub use clocks::{WasiClocks, WasiMonotonicClock, WasiWallClock};
-pub use ctx::{WasiCtx, WasiCtxBuilder, WasiView};
+pub use ctx::{CapturedStdio, ShutdownReport, WasiCtx, WasiCtxBuilder, WasiView, Watchdog};
 pub use error::I32Exit;
 pub use filesystem::{DirPerms, FilePerms};
+pub use preview2::poll::{PollStats, PollableInfo, TablePollableExt};
+pub use sched::{ReadinessScheduler, ReportAllReady, SeededReadinessScheduler};
 pub use stream::{InputStream, OutputStream};
 pub use table::{Table, TableError};
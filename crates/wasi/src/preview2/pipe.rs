@@ -8,13 +8,47 @@
 //! but the virtual pipes can be instantiated with any `Read` or `Write` type.
 //!
 use crate::preview2::stream::{InputStream, OutputStream};
-use anyhow::Error;
 use std::any::Any;
+use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::future::Future;
 use std::io::{self, Read, Write};
-use std::sync::{Arc, RwLock};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
 use system_interface::io::ReadReady;
 
+/// Errors produced by the streams in this module, and by the
+/// [`timing`](crate::preview2::timing) wrappers.
+///
+/// Stream methods return these wrapped in an [`anyhow::Error`]; use
+/// `downcast_ref::<pipe::Error>()` to tell the failure modes apart.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// The other end of the pipe, or the receiving end of a channel, has been
+    /// dropped, so no more data can be delivered.
+    #[error("pipe closed")]
+    Closed,
+    /// The underlying reader or writer is in non-blocking mode and can't make
+    /// progress without waiting, or a deadline passed before it could.
+    #[error("operation would block")]
+    WouldBlock,
+    /// An error from the underlying reader or writer.
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Error::WouldBlock
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
 /// A virtual pipe read end.
 ///
 /// This reads from a source that implements the [`Read`] trait. It
@@ -33,12 +67,14 @@ use system_interface::io::ReadReady;
 #[derive(Debug)]
 pub struct ReadPipe<R: Read + ReadReady> {
     reader: Arc<RwLock<R>>,
+    closed: Arc<AtomicBool>,
 }
 
 impl<R: Read + ReadReady> Clone for ReadPipe<R> {
     fn clone(&self) -> Self {
         Self {
             reader: self.reader.clone(),
+            closed: self.closed.clone(),
         }
     }
 }
@@ -55,7 +91,24 @@ impl<R: Read + ReadReady> ReadPipe<R> {
     ///
     /// All `Handle` read operations delegate to reading from this underlying reader.
     pub fn from_shared(reader: Arc<RwLock<R>>) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Make this pipe and all of its clones report the end of the stream from
+    /// now on, regardless of what the underlying reader would return.
+    ///
+    /// This simulates a producer finishing without touching the underlying
+    /// reader, which may be shared. `ReadPipe` does not buffer, so any bytes
+    /// the reader still holds are not delivered after the close.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
     }
 
     /// Try to convert this `ReadPipe<R>` back to the underlying `R` type.
@@ -105,28 +158,38 @@ impl<R: Read + ReadReady + Any + Send + Sync> InputStream for ReadPipe<R> {
         self
     }
 
-    async fn num_ready_bytes(&self) -> Result<u64, Error> {
-        Ok(self.borrow().num_ready_bytes()?)
+    async fn num_ready_bytes(&self) -> Result<u64, anyhow::Error> {
+        if self.is_closed() {
+            return Ok(0);
+        }
+        Ok(self.borrow().num_ready_bytes().map_err(Error::from)?)
     }
 
-    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), anyhow::Error> {
+        if self.is_closed() {
+            return Ok((0, true));
+        }
         match self.borrow().read(buf) {
             Ok(0) => Ok((0, true)),
             Ok(n) => Ok((n.try_into()?, false)),
             Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok((0, false)),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(Error::from(e).into()),
         }
     }
 
-    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
+    async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), anyhow::Error> {
+        if self.is_closed() {
+            return Ok((0, true));
+        }
         let num = io::copy(
             &mut io::Read::take(&mut *self.borrow(), nelem),
             &mut io::sink(),
-        )?;
+        )
+        .map_err(Error::from)?;
         Ok((num, num < nelem))
     }
 
-    async fn readable(&self) -> Result<(), Error> {
+    async fn readable(&self) -> Result<(), anyhow::Error> {
         Ok(())
     }
 }
@@ -147,12 +210,18 @@ impl<R: Read + ReadReady + Any + Send + Sync> InputStream for ReadPipe<R> {
 #[derive(Debug)]
 pub struct WritePipe<W: Write> {
     writer: Arc<RwLock<W>>,
+    unbuffered: bool,
+    /// Tasks to wake after the next write, e.g. to check a
+    /// [`threshold`](WritePipe::threshold).
+    wakers: Arc<Mutex<Vec<Waker>>>,
 }
 
 impl<W: Write> Clone for WritePipe<W> {
     fn clone(&self) -> Self {
         Self {
             writer: self.writer.clone(),
+            unbuffered: self.unbuffered,
+            wakers: self.wakers.clone(),
         }
     }
 }
@@ -169,7 +238,22 @@ impl<W: Write> WritePipe<W> {
     ///
     /// All `Handle` write operations delegate to writing to this underlying writer.
     pub fn from_shared(writer: Arc<RwLock<W>>) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            unbuffered: false,
+            wakers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Flush the underlying writer after every write.
+    ///
+    /// Use this when `W` buffers internally (e.g. a `BufWriter` or
+    /// `LineWriter`) and the consumer needs each guest write forwarded as soon
+    /// as it happens, such as when streaming output to a client. Flushing on
+    /// every write trades throughput for latency, so it is off by default.
+    pub fn unbuffered(mut self) -> Self {
+        self.unbuffered = true;
+        self
     }
 
     /// Try to convert this `WritePipe<W>` back to the underlying `W` type.
@@ -188,6 +272,12 @@ impl<W: Write> WritePipe<W> {
     fn borrow(&self) -> std::sync::RwLockWriteGuard<W> {
         RwLock::write(&self.writer).unwrap()
     }
+
+    fn wake(&self) {
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
 }
 
 impl WritePipe<io::Cursor<Vec<u8>>> {
@@ -195,6 +285,85 @@ impl WritePipe<io::Cursor<Vec<u8>>> {
     pub fn new_in_memory() -> Self {
         Self::new(io::Cursor::new(vec![]))
     }
+
+    /// Like [`new_in_memory`](Self::new_in_memory), but with room for
+    /// `capacity` bytes before the buffer has to grow.
+    pub fn new_in_memory_with_capacity(capacity: usize) -> Self {
+        Self::new(io::Cursor::new(Vec::with_capacity(capacity)))
+    }
+
+    /// Discard everything written to the buffer so far, keeping its
+    /// allocation for reuse.
+    ///
+    /// The buffer is shared between all clones of this pipe, so the clear is
+    /// visible through every handle, including one installed in a `WasiCtx`.
+    pub fn clear(&self) {
+        let mut cursor = self.borrow();
+        cursor.get_mut().clear();
+        cursor.set_position(0);
+    }
+
+    /// Copy everything written to the buffer so far, leaving it in place.
+    pub fn contents(&self) -> Vec<u8> {
+        self.borrow().get_ref().clone()
+    }
+
+    /// Take everything written to the buffer so far, leaving it empty.
+    ///
+    /// Like [`clear`](Self::clear), this is visible through every clone of
+    /// this pipe, so a capture sink can be drained between guest runs without
+    /// rebuilding the `WasiCtx`.
+    pub fn take_contents(&self) -> Vec<u8> {
+        let mut cursor = self.borrow();
+        cursor.set_position(0);
+        std::mem::take(cursor.get_mut())
+    }
+
+    /// Create a read pipe that replays everything written to the buffer so
+    /// far, e.g. to feed one guest's captured output to another guest.
+    ///
+    /// The contents are copied, so other clones of this pipe, including one
+    /// installed in a `WasiCtx`, keep capturing, and the returned pipe does
+    /// not see their later writes.
+    pub fn into_input(self) -> ReadPipe<io::Cursor<Vec<u8>>> {
+        ReadPipe::from(self.borrow().get_ref().clone())
+    }
+
+    /// Wait until the buffer holds at least `n` bytes, e.g. to process the
+    /// guest's output in batches as it is produced.
+    ///
+    /// The future holds a clone of this pipe, so
+    /// [`try_into_inner`](Self::try_into_inner) fails while it is pending.
+    /// Since [`take_contents`](Self::take_contents) and
+    /// [`clear`](Self::clear) empty the buffer, the count restarts from zero
+    /// after either is called.
+    pub fn threshold(&self, n: usize) -> impl Future<Output = ()> + Send + 'static {
+        Threshold {
+            pipe: self.clone(),
+            n,
+        }
+    }
+}
+
+/// The future returned by [`WritePipe::threshold`].
+struct Threshold {
+    pipe: WritePipe<io::Cursor<Vec<u8>>>,
+    n: usize,
+}
+
+impl Future for Threshold {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Hold the waker list while checking the length, so a write landing
+        // in between can't miss this waker.
+        let mut wakers = self.pipe.wakers.lock().unwrap();
+        if self.pipe.borrow().get_ref().len() >= self.n {
+            return Poll::Ready(());
+        }
+        wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
 }
 
 #[async_trait::async_trait]
@@ -203,8 +372,14 @@ impl<W: Write + Any + Send + Sync> OutputStream for WritePipe<W> {
         self
     }
 
-    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
-        let n = self.borrow().write(buf)?;
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, anyhow::Error> {
+        let mut writer = self.borrow();
+        let n = writer.write(buf).map_err(Error::from)?;
+        if self.unbuffered {
+            writer.flush().map_err(Error::from)?;
+        }
+        drop(writer);
+        self.wake();
         Ok(n.try_into()?)
     }
 
@@ -214,20 +389,1134 @@ impl<W: Write + Any + Send + Sync> OutputStream for WritePipe<W> {
         &mut self,
         src: &mut dyn InputStream,
         nelem: u64,
-    ) -> Result<u64, Error> {
+    ) -> Result<u64, anyhow::Error> {
         todo!()
     }
     */
 
-    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
-        let num = io::copy(
-            &mut io::Read::take(io::repeat(0), nelem),
-            &mut *self.borrow(),
-        )?;
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, anyhow::Error> {
+        let mut writer = self.borrow();
+        let num = io::copy(&mut io::Read::take(io::repeat(0), nelem), &mut *writer)
+            .map_err(Error::from)?;
+        if self.unbuffered {
+            writer.flush().map_err(Error::from)?;
+        }
+        drop(writer);
+        self.wake();
         Ok(num)
     }
 
-    async fn writable(&self) -> Result<(), Error> {
+    async fn shutdown(&mut self) -> Result<(), anyhow::Error> {
+        self.borrow().flush().map_err(Error::from)?;
+        Ok(())
+    }
+
+    async fn sync_data(&mut self) -> Result<(), anyhow::Error> {
+        self.borrow().flush().map_err(Error::from)?;
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// State shared between the two ends of a [`pipe`].
+#[derive(Debug)]
+struct PipeState {
+    /// Messages written by the `OutputPipe` that the `InputPipe` has not yet
+    /// picked up.
+    queue: VecDeque<Message>,
+    /// The maximum number of messages `queue` may hold.
+    bound: usize,
+    /// The maximum number of bytes `queue` may hold.
+    max_bytes: usize,
+    /// The total length of the messages in `queue`, plus the unread part of a
+    /// message the `InputPipe` has taken off it but not finished reading.
+    queued_bytes: usize,
+    /// Set when the `OutputPipe` is dropped. The `InputPipe` reports the end
+    /// of the stream once the queue has drained.
+    writer_closed: bool,
+    /// Set when the `InputPipe` is dropped. Further writes fail.
+    reader_closed: bool,
+    /// The number of messages ever queued, and ever taken off the queue by
+    /// the `InputPipe`, which identify the messages for
+    /// [`OutputPipe::write_acked`].
+    pushed: u64,
+    popped: u64,
+    /// Tasks in [`OutputPipe::write_acked`] to wake when a message is taken
+    /// off the queue or the reader is dropped.
+    ack_wakers: Vec<Waker>,
+}
+
+impl PipeState {
+    fn push(&mut self, message: Message) {
+        self.queued_bytes += message.len();
+        self.queue.push_back(message);
+        self.pushed += 1;
+    }
+
+    fn wake_ack_waiters(&mut self) {
+        for waker in self.ack_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The longest write a [`Message`] stores inline.
+const INLINE_MESSAGE_LEN: usize = 32;
+
+/// One write queued in a [`pipe`].
+///
+/// Guests often write a few bytes at a time, e.g. one line of output, so
+/// short writes are stored inline rather than each getting its own heap
+/// allocation. The reader copies them into its buffer, whose allocation is
+/// reused from one message to the next.
+#[derive(Debug)]
+enum Message {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_MESSAGE_LEN],
+    },
+    Heap(Vec<u8>),
+}
+
+impl Message {
+    fn new(buf: &[u8]) -> Self {
+        if buf.len() <= INLINE_MESSAGE_LEN {
+            let mut bytes = [0; INLINE_MESSAGE_LEN];
+            bytes[..buf.len()].copy_from_slice(buf);
+            Message::Inline {
+                len: buf.len() as u8,
+                bytes,
+            }
+        } else {
+            Message::Heap(buf.to_vec())
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Message::Inline { len, bytes } => &bytes[..usize::from(*len)],
+            Message::Heap(bytes) => bytes,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes().len()
+    }
+
+    /// Move the message's bytes into `buffer`, which must be empty.
+    fn move_into(self, buffer: &mut Vec<u8>) {
+        match self {
+            Message::Inline { len, bytes } => buffer.extend_from_slice(&bytes[..usize::from(len)]),
+            Message::Heap(bytes) => *buffer = bytes,
+        }
+    }
+}
+
+/// An input stream that is always at its end, e.g. for a guest with no
+/// stdin.
+///
+/// This is a unit struct, so it holds no state and boxing it, as a `WasiCtx`
+/// does with its stdio, needs no allocation. It is the default stdin of a
+/// [`WasiCtxBuilder`](crate::preview2::WasiCtxBuilder).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClosedInputStream;
+
+impl ClosedInputStream {
+    /// Create a stream that reports the end of the stream on every read.
+    ///
+    /// This is the same as `ClosedInputStream` itself, for use where a
+    /// constructor reads better.
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for ClosedInputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<(u64, bool), anyhow::Error> {
+        Ok((0, true))
+    }
+
+    async fn read_vectored<'a>(
+        &mut self,
+        _bufs: &mut [io::IoSliceMut<'a>],
+    ) -> Result<(u64, bool), anyhow::Error> {
+        Ok((0, true))
+    }
+
+    async fn skip(&mut self, _nelem: u64) -> Result<(u64, bool), anyhow::Error> {
+        Ok((0, true))
+    }
+
+    async fn readable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// An output stream that accepts and discards everything written to it.
+///
+/// Like [`ClosedInputStream`], this is a unit struct that needs no
+/// allocation. It is the default stdout and stderr of a
+/// [`WasiCtxBuilder`](crate::preview2::WasiCtxBuilder).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiscardOutputStream;
+
+impl DiscardOutputStream {
+    /// Create a stream that discards everything written to it.
+    ///
+    /// This is the same as `DiscardOutputStream` itself, for use where a
+    /// constructor reads better.
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for DiscardOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, anyhow::Error> {
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[io::IoSlice<'a>]) -> Result<u64, anyhow::Error> {
+        Ok(bufs.iter().map(|buf| buf.len()).sum::<usize>().try_into()?)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, anyhow::Error> {
+        Ok(nelem)
+    }
+
+    async fn writable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Create an in-process pipe.
+///
+/// Bytes written to the returned [`OutputPipe`] can be read from the returned
+/// [`InputPipe`]. Each write is queued as one message, and at most `bound`
+/// messages may be queued at once; writes made while the queue is full accept
+/// zero bytes. Dropping the `OutputPipe` ends the stream for the `InputPipe`
+/// once the queue drains, and dropping the `InputPipe` makes further writes
+/// fail.
+///
+/// # Panics
+///
+/// Panics if `bound` is zero.
+pub fn pipe(bound: usize) -> (InputPipe, OutputPipe) {
+    assert!(bound > 0, "pipe bound must be nonzero");
+    new_pipe(bound, usize::MAX)
+}
+
+/// Create an in-process pipe that applies back-pressure based on the number
+/// of queued bytes rather than the number of queued writes.
+///
+/// At most `max_bytes` bytes may be queued at once, however they are split
+/// between writes. A write that does not fit accepts only as many bytes as the
+/// remaining budget allows, possibly zero. A write the reader has started on
+/// keeps counting until it has been read in full. This bounds the memory held
+/// by the pipe, which a message-count bound on its own does not. The pipe otherwise
+/// behaves like one created with [`pipe`].
+///
+/// # Panics
+///
+/// Panics if `max_bytes` is zero.
+pub fn byte_bounded_pipe(max_bytes: usize) -> (InputPipe, OutputPipe) {
+    assert!(max_bytes > 0, "pipe byte limit must be nonzero");
+    new_pipe(usize::MAX, max_bytes)
+}
+
+fn new_pipe(bound: usize, max_bytes: usize) -> (InputPipe, OutputPipe) {
+    let state = Arc::new(Mutex::new(PipeState {
+        queue: VecDeque::new(),
+        bound,
+        max_bytes,
+        queued_bytes: 0,
+        writer_closed: false,
+        reader_closed: false,
+        pushed: 0,
+        popped: 0,
+        ack_wakers: Vec::new(),
+    }));
+    (
+        InputPipe {
+            state: state.clone(),
+            buffer: Vec::new(),
+            queued_in_buffer: 0,
+        },
+        OutputPipe {
+            state,
+            closed_as_eof: false,
+            eof_reported: false,
+        },
+    )
+}
+
+/// Create a pair of connected bidirectional endpoints.
+///
+/// This is the in-process analog of a socket pair: each endpoint is an
+/// `(InputPipe, OutputPipe)`, and bytes written to one endpoint's output can be
+/// read from the other endpoint's input. It is built from two [`pipe`]s with
+/// the given `bound`, so dropping one endpoint's output ends the stream on the
+/// other endpoint's input.
+pub fn duplex(bound: usize) -> ((InputPipe, OutputPipe), (InputPipe, OutputPipe)) {
+    let (a_input, b_output) = pipe(bound);
+    let (b_input, a_output) = pipe(bound);
+    ((a_input, a_output), (b_input, b_output))
+}
+
+/// Create a stdin and stdout pair for a single guest, where everything the
+/// guest writes to stdout can be read back from its stdin.
+///
+/// The pipe is unbounded, so a write never waits for a read: a guest that
+/// writes a lot before reading anything back would otherwise block forever
+/// on its own output. Writes are queued immediately, so there is no
+/// unflushed output to wait for either. While nothing is queued, reads
+/// return zero bytes; the input ends once the guest drops its stdout.
+pub fn echo_loopback() -> (InputPipe, EchoOutputStream) {
+    let (input, output) = new_pipe(usize::MAX, usize::MAX);
+    (
+        input,
+        EchoOutputStream {
+            output,
+            transform: None,
+        },
+    )
+}
+
+/// The output end of an [`echo_loopback`].
+pub struct EchoOutputStream {
+    output: OutputPipe,
+    transform: Option<Box<dyn FnMut(&[u8]) -> Vec<u8> + Send + Sync>>,
+}
+
+impl EchoOutputStream {
+    /// Pass each write through `transform` on its way back to the input,
+    /// e.g. to upper-case it.
+    ///
+    /// Writes are split wherever the guest splits them, so the transform
+    /// should work byte by byte rather than rely on seeing whole lines.
+    pub fn transform(
+        mut self,
+        transform: impl FnMut(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for EchoOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn shutdown(&mut self) -> Result<(), anyhow::Error> {
+        self.output.shutdown().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, anyhow::Error> {
+        match &mut self.transform {
+            Some(transform) => {
+                let echoed = transform(buf);
+                self.output.write(&echoed).await?;
+            }
+            None => {
+                self.output.write(buf).await?;
+            }
+        }
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn writable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// The read end of a [`pipe`].
+#[derive(Debug)]
+pub struct InputPipe {
+    state: Arc<Mutex<PipeState>>,
+    /// The unread remainder of the message most recently taken off the queue.
+    buffer: Vec<u8>,
+    /// How many bytes at the end of `buffer` still count toward the pipe's
+    /// byte limit, so the writer only gets room back as they are read.
+    queued_in_buffer: usize,
+}
+
+impl InputPipe {
+    /// Make `initial` the first bytes read from this end, ahead of anything
+    /// written to the [`OutputPipe`].
+    ///
+    /// Unlike writing the bytes to the `OutputPipe` first, this does not use
+    /// up room in the queue, so the guest's first read sees them even when
+    /// the writer is filling the queue concurrently.
+    pub fn with_initial_buffer(mut self, mut initial: Vec<u8>) -> Self {
+        initial.append(&mut self.buffer);
+        self.buffer = initial;
+        self
+    }
+
+    /// The number of writes queued by the [`OutputPipe`] that this end has
+    /// not yet started reading.
+    pub fn queued_messages(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+}
+
+impl Drop for InputPipe {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.reader_closed = true;
+            state.wake_ack_waiters();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for InputPipe {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), anyhow::Error> {
+        if self.buffer.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            match state.queue.pop_front() {
+                Some(message) => {
+                    // When the whole message fits, which is the common case,
+                    // copy it straight out rather than through the buffer.
+                    let fits = message.len() <= buf.len();
+                    if fits {
+                        state.queued_bytes -= message.len();
+                    }
+                    state.popped += 1;
+                    state.wake_ack_waiters();
+                    drop(state);
+                    if fits {
+                        let bytes = message.bytes();
+                        buf[..bytes.len()].copy_from_slice(bytes);
+                        return Ok((bytes.len().try_into()?, false));
+                    }
+                    self.queued_in_buffer = message.len();
+                    message.move_into(&mut self.buffer);
+                }
+                None => return Ok((0, state.writer_closed)),
+            }
+        }
+        let unqueued = self.buffer.len() - self.queued_in_buffer;
+        let n = buf.len().min(self.buffer.len());
+        buf[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        let released = n.saturating_sub(unqueued);
+        if released > 0 {
+            self.queued_in_buffer -= released;
+            let mut state = self.state.lock().unwrap();
+            state.queued_bytes -= released;
+            state.wake_ack_waiters();
+        }
+        Ok((n.try_into()?, false))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let unqueued = self.buffer.len() - self.queued_in_buffer;
+        Ok((unqueued + state.queued_bytes).try_into()?)
+    }
+
+    async fn readable(&self) -> Result<(), anyhow::Error> {
         Ok(())
     }
 }
+
+/// The write end of a [`pipe`].
+///
+/// Writes are queued for the [`InputPipe`] immediately rather than buffered
+/// on this end, so a small write is visible to the reader without the guest
+/// having to poll or write again.
+#[derive(Debug)]
+pub struct OutputPipe {
+    state: Arc<Mutex<PipeState>>,
+    /// Whether the first write after the reader is dropped accepts zero
+    /// bytes rather than failing.
+    closed_as_eof: bool,
+    /// Whether a write has accepted zero bytes because of `closed_as_eof`.
+    eof_reported: bool,
+}
+
+impl OutputPipe {
+    /// Close this end of the pipe without dropping it, like a TCP half-close.
+    ///
+    /// The reader sees the end of the stream once it has read everything
+    /// already queued, and further writes fail with [`Error::Closed`]. On a
+    /// [`duplex`] endpoint, this signals the end of a request while leaving
+    /// the endpoint's input open to read the reply.
+    pub fn shutdown_write(&self) {
+        self.state.lock().unwrap().writer_closed = true;
+    }
+
+    /// Make the first write made after the [`InputPipe`] is dropped accept
+    /// zero bytes instead of failing with [`Error::Closed`].
+    ///
+    /// The guest then sees a zero-byte write, like the end of the stream,
+    /// rather than a stream error. Only the first write does so: a guest that
+    /// keeps writing anyway, e.g. because it takes the zero for a full pipe,
+    /// gets the stream error from the next one rather than spinning forever. Since a full pipe also accepts zero bytes, use
+    /// [`is_closed`](Self::is_closed) to tell the two apart.
+    pub fn closed_as_eof(mut self) -> Self {
+        self.closed_as_eof = true;
+        self
+    }
+
+    /// Queue at most `limit` bytes at once, on top of any limit the pipe was
+    /// created with. A write that does not fit accepts only as many bytes as
+    /// the remaining room allows, possibly zero, as with
+    /// [`byte_bounded_pipe`]. Bytes already queued beyond a lowered limit stay
+    /// queued, and writes accept nothing until the reader has caught up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn with_max_buffer(self, limit: usize) -> Self {
+        assert!(limit > 0, "max buffer must be nonzero");
+        let mut state = self.state.lock().unwrap();
+        state.max_bytes = state.max_bytes.min(limit);
+        drop(state);
+        self
+    }
+
+    /// Whether either end of the pipe has been closed, so that no further
+    /// writes will be read.
+    pub fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.reader_closed || state.writer_closed
+    }
+
+    /// Write all of `buf` as one message, and wait until the [`InputPipe`]
+    /// has taken it off the queue, e.g. to confirm that a response was
+    /// delivered before carrying on.
+    ///
+    /// Unlike a write through [`OutputStream`], this waits for room in the
+    /// queue rather than accepting zero bytes. A message longer than the
+    /// pipe's byte limit is queued once the queue is empty. This fails with
+    /// [`Error::Closed`] if the pipe is closed before the message is queued,
+    /// or the reader is dropped before taking it. Dropping the returned
+    /// future once the message is queued leaves it queued.
+    pub async fn write_acked(&mut self, buf: &[u8]) -> Result<(), anyhow::Error> {
+        let mut seq = None;
+        std::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            let seq = match seq {
+                Some(seq) => seq,
+                None => {
+                    if state.reader_closed || state.writer_closed {
+                        return Poll::Ready(Err(Error::Closed.into()));
+                    }
+                    let fits = state.queue.is_empty()
+                        || (state.queue.len() < state.bound
+                            && buf.len() <= state.max_bytes.saturating_sub(state.queued_bytes));
+                    if !fits {
+                        state.ack_wakers.push(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                    state.push(Message::new(buf));
+                    *seq.insert(state.pushed - 1)
+                }
+            };
+            if state.popped > seq {
+                Poll::Ready(Ok(()))
+            } else if state.reader_closed {
+                Poll::Ready(Err(Error::Closed.into()))
+            } else {
+                state.ack_wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for OutputPipe {
+    /// Writes are queued as they happen, so nothing is lost here: the
+    /// `InputPipe` still sees every queued message before the end of the
+    /// stream.
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.writer_closed = true;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for OutputPipe {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.reader_closed && !state.writer_closed && self.closed_as_eof && !self.eof_reported {
+            self.eof_reported = true;
+            return Ok(0);
+        }
+        if state.reader_closed || state.writer_closed {
+            return Err(Error::Closed.into());
+        }
+        // An oversized `write_acked` message may have left more queued than the
+        // limit allows.
+        let n = buf
+            .len()
+            .min(state.max_bytes.saturating_sub(state.queued_bytes));
+        if n == 0 || state.queue.len() >= state.bound {
+            return Ok(0);
+        }
+        state.push(Message::new(&buf[..n]));
+        Ok(n.try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), anyhow::Error> {
+        self.shutdown_write();
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// An output stream that sends each write to a [`std::sync::mpsc`] channel.
+///
+/// This lets synchronous host code consume guest output on a thread of its
+/// own. With an unbounded [`Sender`](std::sync::mpsc::Sender) every write is
+/// accepted immediately. With a bounded
+/// [`SyncSender`](std::sync::mpsc::SyncSender), a write made while the channel
+/// is full accepts zero bytes rather than blocking the guest. Writes fail once
+/// the receiver has been dropped.
+pub struct ChannelOutputStream {
+    // The senders are only `Sync` on newer Rust versions, so guard them with a
+    // mutex, which is uncontended since writes take `&mut self`.
+    sender: Mutex<ChannelSender>,
+}
+
+enum ChannelSender {
+    Unbounded(std::sync::mpsc::Sender<Vec<u8>>),
+    Bounded(std::sync::mpsc::SyncSender<Vec<u8>>),
+}
+
+impl ChannelOutputStream {
+    /// Create a stream sending to an unbounded channel.
+    pub fn new(sender: std::sync::mpsc::Sender<Vec<u8>>) -> Self {
+        Self {
+            sender: Mutex::new(ChannelSender::Unbounded(sender)),
+        }
+    }
+
+    /// Create a stream sending to a bounded channel.
+    pub fn bounded(sender: std::sync::mpsc::SyncSender<Vec<u8>>) -> Self {
+        Self {
+            sender: Mutex::new(ChannelSender::Bounded(sender)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for ChannelOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, anyhow::Error> {
+        use std::sync::mpsc::TrySendError;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sender.get_mut().unwrap() {
+            ChannelSender::Unbounded(sender) => {
+                sender.send(buf.to_vec()).map_err(|_| Error::Closed)?
+            }
+            ChannelSender::Bounded(sender) => match sender.try_send(buf.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return Ok(0),
+                Err(TrySendError::Disconnected(_)) => return Err(Error::Closed.into()),
+            },
+        }
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn writable(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::stream::test_util::poll_once;
+
+    #[test]
+    fn in_memory_write_pipe_take_contents() {
+        let pipe = WritePipe::new_in_memory();
+        let shared = pipe.clone();
+        pipe.borrow().write_all(b"first run").unwrap();
+        assert_eq!(shared.take_contents(), b"first run");
+        pipe.borrow().write_all(b"second").unwrap();
+        assert_eq!(pipe.take_contents(), b"second");
+        assert!(shared.take_contents().is_empty());
+    }
+
+    #[test]
+    fn in_memory_write_pipe_clear() {
+        let pipe = WritePipe::new_in_memory();
+        pipe.borrow().write_all(b"discarded").unwrap();
+        pipe.clone().clear();
+        pipe.borrow().write_all(b"kept").unwrap();
+        assert_eq!(pipe.take_contents(), b"kept");
+    }
+
+    #[tokio::test]
+    async fn pipe_round_trip() {
+        let (mut input, mut output) = pipe(2);
+        assert_eq!(output.write(b"hello").await.unwrap(), 5);
+        assert_eq!(output.write(b" world").await.unwrap(), 6);
+        // The queue is full, so this write is not accepted.
+        assert_eq!(output.write(b"!").await.unwrap(), 0);
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 11);
+
+        let mut buf = [0; 3];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf, b"hel");
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (6, false));
+        assert_eq!(&buf[..6], b" world");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, false));
+
+        drop(output);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn duplex_endpoints_are_connected() {
+        let ((mut a_input, mut a_output), (mut b_input, b_output)) = duplex(4);
+        let mut buf = [0; 16];
+
+        a_output.write(b"ping").await.unwrap();
+        assert_eq!(b_input.read(&mut buf).await.unwrap(), (4, false));
+        assert_eq!(&buf[..4], b"ping");
+
+        // Closing one endpoint's output ends the other endpoint's input.
+        drop(b_output);
+        assert_eq!(a_input.read(&mut buf).await.unwrap(), (0, true));
+
+        // Closing one endpoint's input makes the other endpoint's writes fail.
+        drop(b_input);
+        assert!(a_output.write(b"lost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn small_write_is_not_stranded() {
+        let (mut input, mut output) = pipe(1);
+        assert_eq!(output.write(b"x").await.unwrap(), 1);
+        // No poll or further write happens on the output end.
+        let mut buf = [0; 4];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (1, false));
+        assert_eq!(buf[0], b'x');
+    }
+
+    #[tokio::test]
+    async fn byte_bounded_pipe_limits_queued_bytes() {
+        let (mut input, mut output) = byte_bounded_pipe(8);
+        // Many small writes fit, regardless of how many there are.
+        for _ in 0..4 {
+            assert_eq!(output.write(b"a").await.unwrap(), 1);
+        }
+        // A large write only fills the remaining budget.
+        assert_eq!(output.write(b"bbbbbbbb").await.unwrap(), 4);
+        assert_eq!(output.write(b"c").await.unwrap(), 0);
+
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (1, false));
+        assert_eq!(output.write(b"cc").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn read_pipe_close() {
+        let pipe = ReadPipe::from("hello");
+        let mut guest = pipe.clone();
+        let mut buf = [0; 2];
+        assert_eq!(guest.read(&mut buf).await.unwrap(), (2, false));
+        pipe.close();
+        assert_eq!(guest.read(&mut buf).await.unwrap(), (0, true));
+        assert_eq!(guest.num_ready_bytes().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_output_pipe_keeps_queued_data() {
+        let (mut input, mut output) = pipe(4);
+        output.write(b"last words").await.unwrap();
+        drop(output);
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (10, false));
+        assert_eq!(&buf[..10], b"last words");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn channel_output_stream() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut stream = ChannelOutputStream::new(sender);
+        assert_eq!(stream.write(b"hello").await.unwrap(), 5);
+        assert_eq!(receiver.try_recv().unwrap(), b"hello");
+        drop(receiver);
+        assert!(stream.write(b"lost").await.is_err());
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        let mut stream = ChannelOutputStream::bounded(sender);
+        assert_eq!(stream.write(b"one").await.unwrap(), 3);
+        assert_eq!(stream.write(b"two").await.unwrap(), 0);
+        assert_eq!(receiver.recv().unwrap(), b"one");
+        assert_eq!(stream.write(b"two").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn input_pipe_queued_messages() {
+        let (mut input, mut output) = pipe(4);
+        assert_eq!(input.queued_messages(), 0);
+        output.write(b"one").await.unwrap();
+        output.write(b"two").await.unwrap();
+        assert_eq!(input.queued_messages(), 2);
+        let mut buf = [0; 1];
+        input.read(&mut buf).await.unwrap();
+        assert_eq!(input.queued_messages(), 1);
+    }
+
+    #[tokio::test]
+    async fn pipe_errors_can_be_matched() {
+        let (input, mut output) = pipe(4);
+        drop(input);
+        let err = output.write(b"lost").await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Closed)));
+
+        struct Blocked;
+        impl Write for Blocked {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = WritePipe::new(Blocked).write(b"x").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::WouldBlock)
+        ));
+    }
+
+    #[tokio::test]
+    async fn in_memory_write_pipe_into_input() {
+        let capture = WritePipe::new_in_memory();
+        let mut stdout = capture.clone();
+        stdout.write(b"replayed").await.unwrap();
+        let mut input = capture.into_input();
+        stdout.write(b" later").await.unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (8, false));
+        assert_eq!(&buf[..8], b"replayed");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn in_memory_write_pipe_threshold() {
+        let capture = WritePipe::new_in_memory();
+        let mut stdout = capture.clone();
+        let mut reached = Box::pin(capture.threshold(5));
+        stdout.write(b"abc").await.unwrap();
+        tokio::select! {
+            biased;
+            _ = &mut reached => panic!("threshold reached early"),
+            _ = crate::preview2::stream::yield_now() => {}
+        }
+        stdout.write(b"de").await.unwrap();
+        reached.await;
+    }
+
+    #[tokio::test]
+    async fn duplex_half_close() {
+        let ((mut client_input, mut client_output), (mut server_input, mut server_output)) =
+            duplex(4);
+        let mut buf = [0; 16];
+
+        client_output.write(b"request").await.unwrap();
+        client_output.shutdown_write();
+        assert!(client_output.write(b"more").await.is_err());
+        assert_eq!(server_input.read(&mut buf).await.unwrap(), (7, false));
+        assert_eq!(server_input.read(&mut buf).await.unwrap(), (0, true));
+
+        server_output.write(b"reply").await.unwrap();
+        assert_eq!(client_input.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"reply");
+    }
+
+    #[tokio::test]
+    async fn output_pipe_shutdown_ends_stream() {
+        let (mut input, mut output) = pipe(4);
+        output.write(b"last").await.unwrap();
+        output.shutdown().await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (4, false));
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn sync_is_a_no_op_for_in_memory_pipes() {
+        let mut capture = WritePipe::new_in_memory();
+        capture.write(b"kept").await.unwrap();
+        capture.sync_data().await.unwrap();
+        capture.sync_all().await.unwrap();
+        assert_eq!(capture.take_contents(), b"kept");
+
+        let (_input, mut output) = pipe(1);
+        output.sync_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_reader_can_look_like_eof() {
+        let (input, output) = pipe(4);
+        let mut output = output.closed_as_eof();
+        assert!(!output.is_closed());
+        assert_eq!(output.write(b"read").await.unwrap(), 4);
+        drop(input);
+        assert!(output.is_closed());
+        assert_eq!(output.write(b"lost").await.unwrap(), 0);
+        // Only the first write after the close looks like the end.
+        assert!(output.write(b"again").await.is_err());
+
+        // Writing after shutting down this end is still a mistake.
+        let (input, output) = pipe(4);
+        let mut output = output.closed_as_eof();
+        drop(input);
+        output.shutdown_write();
+        assert!(output.write(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn initial_buffer_is_read_first() {
+        let (input, mut output) = pipe(1);
+        let mut input = input.with_initial_buffer(b"first ".to_vec());
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 6);
+        assert_eq!(output.write(b"second").await.unwrap(), 6);
+        drop(output);
+
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (6, false));
+        assert_eq!(&buf[..6], b"first ");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (6, false));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn echo_loopback_returns_output_as_input() {
+        let (mut input, output) = echo_loopback();
+        let mut output = output.transform(|bytes| bytes.to_ascii_uppercase());
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (0, false));
+
+        // Writes never wait for the guest to read them back.
+        for _ in 0..100 {
+            assert_eq!(output.write(b"echo ").await.unwrap(), 5);
+        }
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 500);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"ECHO ");
+
+        drop(output);
+        let mut rest = 0;
+        loop {
+            match input.read(&mut buf).await.unwrap() {
+                (0, true) => break,
+                (n, _) => rest += n,
+            }
+        }
+        assert_eq!(rest, 495);
+    }
+
+    #[tokio::test]
+    async fn pipe_mixes_short_and_long_writes() {
+        let (mut input, mut output) = pipe(8);
+        let long = vec![b'x'; 100];
+        output.write(b"short").await.unwrap();
+        output.write(&long).await.unwrap();
+        output.write(b"").await.unwrap();
+        output.write(&[b'y'; 32]).await.unwrap();
+        drop(output);
+
+        let mut read = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match input.read(&mut buf).await.unwrap() {
+                (_, true) => break,
+                (n, false) => read.extend_from_slice(&buf[..n as usize]),
+            }
+        }
+        let mut expected = b"short".to_vec();
+        expected.extend(&long);
+        expected.extend([b'y'; 32]);
+        assert_eq!(read, expected);
+    }
+
+    #[tokio::test]
+    async fn write_acked_waits_for_the_reader() {
+        let (mut input, mut output) = pipe(1);
+        let mut buf = [0; 16];
+        {
+            let mut write = Box::pin(output.write_acked(b"response"));
+            assert!(poll_once(&mut write).await.is_pending());
+            assert_eq!(input.read(&mut buf[..3]).await.unwrap(), (3, false));
+            // Taking any of the message off the queue acknowledges it.
+            assert!(poll_once(&mut write).await.is_ready());
+        }
+        assert_eq!(input.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"ponse");
+
+        // With the queue full, the message waits for room first.
+        assert_eq!(output.write(b"queued").await.unwrap(), 6);
+        let mut write = Box::pin(output.write_acked(b"next"));
+        assert!(poll_once(&mut write).await.is_pending());
+        assert_eq!(input.queued_messages(), 1);
+        input.read(&mut buf).await.unwrap();
+        assert!(poll_once(&mut write).await.is_pending());
+        assert_eq!(input.queued_messages(), 1);
+        input.read(&mut buf).await.unwrap();
+        assert!(poll_once(&mut write).await.is_ready());
+    }
+
+    #[tokio::test]
+    async fn write_acked_queues_oversized_messages_alone() {
+        let (mut input, mut output) = byte_bounded_pipe(4);
+        let mut buf = [0; 16];
+        {
+            let mut write = Box::pin(output.write_acked(b"oversized"));
+            assert!(poll_once(&mut write).await.is_pending());
+        }
+        // The queue holds more than the limit, so nothing else fits.
+        assert_eq!(output.write(b"more").await.unwrap(), 0);
+        let mut write = Box::pin(output.write_acked(b"next"));
+        assert!(poll_once(&mut write).await.is_pending());
+        assert_eq!(input.queued_messages(), 1);
+
+        assert_eq!(input.read(&mut buf).await.unwrap(), (9, false));
+        assert!(poll_once(&mut write).await.is_pending());
+        input.read(&mut buf).await.unwrap();
+        assert!(poll_once(&mut write).await.is_ready());
+        drop(write);
+        assert_eq!(output.write(b"more").await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn write_acked_fails_when_the_reader_is_dropped() {
+        let (input, mut output) = pipe(4);
+        let mut write = Box::pin(output.write_acked(b"lost"));
+        assert!(poll_once(&mut write).await.is_pending());
+        drop(input);
+        match poll_once(&mut write).await {
+            Poll::Ready(Err(err)) => {
+                assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Closed)))
+            }
+            _ => panic!("write should have failed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn output_pipe_max_buffer() {
+        let (mut input, output) = pipe(8);
+        let mut output = output.with_max_buffer(4);
+        assert_eq!(output.write(b"abc").await.unwrap(), 3);
+        assert_eq!(output.write(b"def").await.unwrap(), 1);
+        assert_eq!(output.write(b"ef").await.unwrap(), 0);
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(output.write(b"ef").await.unwrap(), 2);
+
+        // Lowering the limit below what is queued keeps what is queued.
+        let mut output = output.with_max_buffer(1);
+        assert_eq!(output.write(b"g").await.unwrap(), 0);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (1, false));
+        assert_eq!(output.write(b"g").await.unwrap(), 0);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(output.write(b"gh").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn byte_bounded_pipe_counts_partly_read_writes() {
+        let (mut input, mut output) = byte_bounded_pipe(4);
+        assert_eq!(output.write(b"abcd").await.unwrap(), 4);
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf[..1]).await.unwrap(), (1, false));
+        // Only the byte read so far has made room.
+        assert_eq!(output.write(b"ef").await.unwrap(), 1);
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 4);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf[..3], b"bcd");
+        assert_eq!(output.write(b"fg").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn pipe_read_of_whole_message_skips_buffer() {
+        let (mut input, mut output) = pipe(4);
+        let message = vec![7; 100];
+        output.write(&message).await.unwrap();
+        let mut buf = [0; 128];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (100, false));
+        assert_eq!(&buf[..100], &message[..]);
+        assert_eq!(input.buffer.capacity(), 0);
+
+        // A message that doesn't fit still goes through the buffer.
+        output.write(&message).await.unwrap();
+        assert_eq!(input.read(&mut buf[..60]).await.unwrap(), (60, false));
+        assert_eq!(input.read(&mut buf).await.unwrap(), (40, false));
+        assert_eq!(&buf[..40], &message[60..]);
+    }
+
+    #[tokio::test]
+    async fn closed_and_discard_streams_are_zero_sized() {
+        // Boxing a zero-sized type never allocates.
+        assert_eq!(std::mem::size_of::<ClosedInputStream>(), 0);
+        assert_eq!(std::mem::size_of::<DiscardOutputStream>(), 0);
+
+        const STDIN: ClosedInputStream = ClosedInputStream::new();
+        let mut stdin: Box<dyn InputStream> = Box::new(STDIN);
+        assert_eq!(stdin.read(&mut [0; 4]).await.unwrap(), (0, true));
+        assert_eq!(stdin.skip(4).await.unwrap(), (0, true));
+
+        let mut stdout: Box<dyn OutputStream> = Box::new(DiscardOutputStream::new());
+        assert_eq!(stdout.write(b"gone").await.unwrap(), 4);
+        assert_eq!(stdout.write_zeroes(1 << 40).await.unwrap(), 1 << 40);
+    }
+
+    #[tokio::test]
+    async fn in_memory_write_pipe_with_capacity() {
+        let pipe = WritePipe::new_in_memory_with_capacity(64);
+        assert!(pipe.borrow().get_ref().capacity() >= 64);
+        let mut stdout = pipe.clone();
+        stdout.write(b"captured").await.unwrap();
+        assert_eq!(pipe.contents(), b"captured");
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(unused_variables)]
 
+use crate::preview2::clocks::{self, civil_from_days, days_from_civil, days_in_month};
 use crate::preview2::preview2::poll::PollableEntry;
 use crate::preview2::wasi::{
     clocks::monotonic_clock::{self, Instant},
@@ -8,14 +9,15 @@ use crate::preview2::wasi::{
     poll::poll::Pollable,
 };
 use crate::preview2::WasiView;
-use cap_std::time::SystemTime;
+use cap_std::time::{Duration, SystemTime};
 
 impl TryFrom<SystemTime> for Datetime {
-    type Error = anyhow::Error;
+    type Error = clocks::Error;
 
     fn try_from(time: SystemTime) -> Result<Self, Self::Error> {
-        let duration =
-            time.duration_since(SystemTime::from_std(std::time::SystemTime::UNIX_EPOCH))?;
+        let duration = time
+            .duration_since(SystemTime::from_std(std::time::SystemTime::UNIX_EPOCH))
+            .map_err(|_| clocks::Error::BeforeEpoch)?;
 
         Ok(Datetime {
             seconds: duration.as_secs(),
@@ -24,6 +26,149 @@ impl TryFrom<SystemTime> for Datetime {
     }
 }
 
+impl TryFrom<Datetime> for SystemTime {
+    type Error = clocks::Error;
+
+    fn try_from(time: Datetime) -> Result<Self, Self::Error> {
+        if time.nanoseconds >= 1_000_000_000 {
+            return Err(clocks::Error::InvalidNanoseconds);
+        }
+        let time = std::time::SystemTime::UNIX_EPOCH
+            .checked_add(Duration::new(time.seconds, time.nanoseconds))
+            .ok_or(clocks::Error::Overflow)?;
+        Ok(SystemTime::from_std(time))
+    }
+}
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A cursor over the bytes of a timestamp being parsed.
+struct Rfc3339<'a>(&'a [u8]);
+
+impl Rfc3339<'_> {
+    fn byte(&mut self, accept: impl Fn(u8) -> bool) -> Option<u8> {
+        let (&b, rest) = self.0.split_first()?;
+        if !accept(b) {
+            return None;
+        }
+        self.0 = rest;
+        Some(b)
+    }
+
+    fn digits(&mut self, n: usize) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| {
+            let digit = self.byte(|b| b.is_ascii_digit())?;
+            Some(acc * 10 + u32::from(digit - b'0'))
+        })
+    }
+
+    fn number(&mut self, n: usize, max: u32) -> Option<u32> {
+        self.digits(n).filter(|v| *v <= max)
+    }
+}
+
+impl Datetime {
+    /// Format this time as an RFC 3339 timestamp in UTC, such as
+    /// `2023-06-01T12:34:56.789Z`.
+    ///
+    /// Fractional seconds are written with as many digits as needed, up to
+    /// nanoseconds, and left out for whole seconds. Nanoseconds past a whole
+    /// second carry over into the seconds.
+    pub fn to_rfc3339(&self) -> String {
+        let seconds = self
+            .seconds
+            .saturating_add(u64::from(self.nanoseconds / 1_000_000_000));
+        let nanoseconds = self.nanoseconds % 1_000_000_000;
+        let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+        let time = seconds % SECONDS_PER_DAY;
+        let mut out = format!(
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+            time / 3_600,
+            time / 60 % 60,
+            time % 60,
+        );
+        if nanoseconds != 0 {
+            let fraction = format!("{nanoseconds:09}");
+            out.push('.');
+            out.push_str(fraction.trim_end_matches('0'));
+        }
+        out.push('Z');
+        out
+    }
+
+    /// Parse an RFC 3339 timestamp, such as `2023-06-01T12:34:56.789Z` or
+    /// `2023-06-01T14:34:56+02:00`.
+    ///
+    /// Fractional seconds beyond nanoseconds are truncated. Leap seconds are
+    /// not supported, and times before the Unix epoch cannot be represented.
+    pub fn from_rfc3339(s: &str) -> Result<Self, clocks::Error> {
+        Self::parse_rfc3339(s.as_bytes()).ok_or(clocks::Error::InvalidFormat)?
+    }
+
+    fn parse_rfc3339(s: &[u8]) -> Option<Result<Self, clocks::Error>> {
+        let mut s = Rfc3339(s);
+        let year = s.digits(4)?;
+        s.byte(|b| b == b'-')?;
+        let month = s.number(2, 12).filter(|m| *m >= 1)?;
+        s.byte(|b| b == b'-')?;
+        let day = s.digits(2)?;
+        if day == 0 || day > days_in_month(year.into(), month) {
+            return None;
+        }
+        s.byte(|b| b.eq_ignore_ascii_case(&b'T'))?;
+        let hour = s.number(2, 23)?;
+        s.byte(|b| b == b':')?;
+        let minute = s.number(2, 59)?;
+        s.byte(|b| b == b':')?;
+        let second = s.number(2, 59)?;
+
+        let mut nanoseconds = 0;
+        if s.byte(|b| b == b'.').is_some() {
+            let mut digits = 0;
+            while let Some(digit) = s.byte(|b| b.is_ascii_digit()) {
+                if digits < 9 {
+                    nanoseconds = nanoseconds * 10 + u32::from(digit - b'0');
+                }
+                digits += 1;
+            }
+            if digits == 0 {
+                return None;
+            }
+            nanoseconds *= 10u32.pow(9u32.saturating_sub(digits));
+        }
+
+        let offset: i64 = match s.byte(|_| true)? {
+            b'Z' | b'z' => 0,
+            sign @ (b'+' | b'-') => {
+                let hours = s.number(2, 23)?;
+                s.byte(|b| b == b':')?;
+                let minutes = s.number(2, 59)?;
+                let offset = i64::from(hours * 3_600 + minutes * 60);
+                if sign == b'-' {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+            _ => return None,
+        };
+        if !s.0.is_empty() {
+            return None;
+        }
+
+        let seconds = days_from_civil(year.into(), month, day) * SECONDS_PER_DAY as i64
+            + i64::from(hour * 3_600 + minute * 60 + second)
+            - offset;
+        Some(match u64::try_from(seconds) {
+            Ok(seconds) => Ok(Datetime {
+                seconds,
+                nanoseconds,
+            }),
+            Err(_) => Err(clocks::Error::BeforeEpoch),
+        })
+    }
+}
+
 #[async_trait::async_trait]
 impl<T: WasiView> wall_clock::Host for T {
     async fn now(&mut self) -> anyhow::Result<Datetime> {
@@ -54,12 +199,24 @@ impl<T: WasiView> monotonic_clock::Host for T {
     }
 
     async fn subscribe(&mut self, when: Instant, absolute: bool) -> anyhow::Result<Pollable> {
+        if let Some(on_subscribe) = &self.ctx().on_subscribe {
+            on_subscribe(when, absolute);
+        }
         Ok(self
             .table_mut()
             .push(Box::new(PollableEntry::MonotonicClock(when, absolute)))?)
     }
 }
 
+/// Reject a `datetime` from the guest whose nanoseconds field is out of
+/// range.
+fn check_nanoseconds(when: &Datetime) -> Result<(), clocks::Error> {
+    if when.nanoseconds >= 1_000_000_000 {
+        return Err(clocks::Error::InvalidNanoseconds);
+    }
+    Ok(())
+}
+
 #[async_trait::async_trait]
 impl<T: WasiView> timezone::Host for T {
     async fn display(
@@ -67,14 +224,206 @@ impl<T: WasiView> timezone::Host for T {
         timezone: Timezone,
         when: Datetime,
     ) -> anyhow::Result<TimezoneDisplay> {
-        todo!()
+        check_nanoseconds(&when)?;
+        let timezone = self.table().get::<clocks::Timezone>(timezone)?;
+        Ok(TimezoneDisplay {
+            utc_offset: timezone.utc_offset_at(when.seconds),
+            name: timezone.name_at(when.seconds).to_string(),
+            in_daylight_saving_time: timezone.is_daylight_saving_time(when.seconds),
+        })
     }
 
     async fn utc_offset(&mut self, timezone: Timezone, when: Datetime) -> anyhow::Result<i32> {
-        todo!()
+        check_nanoseconds(&when)?;
+        let timezone = self.table().get::<clocks::Timezone>(timezone)?;
+        Ok(timezone.utc_offset_at(when.seconds))
     }
 
     async fn drop_timezone(&mut self, timezone: Timezone) -> anyhow::Result<()> {
-        todo!()
+        self.table_mut().delete::<clocks::Timezone>(timezone)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn datetime_round_trip() {
+        let datetime = Datetime {
+            seconds: 1_234_567_890,
+            nanoseconds: 987_654_321,
+        };
+        let time = SystemTime::try_from(datetime).unwrap();
+        let back = Datetime::try_from(time).unwrap();
+        assert_eq!(back.seconds, 1_234_567_890);
+        assert_eq!(back.nanoseconds, 987_654_321);
+    }
+
+    #[test]
+    fn datetime_errors() {
+        let before_epoch = SystemTime::from_std(
+            std::time::SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1),
+        );
+        assert_eq!(
+            Datetime::try_from(before_epoch).err(),
+            Some(clocks::Error::BeforeEpoch)
+        );
+
+        let invalid = Datetime {
+            seconds: 0,
+            nanoseconds: 1_000_000_000,
+        };
+        assert_eq!(
+            SystemTime::try_from(invalid).err(),
+            Some(clocks::Error::InvalidNanoseconds)
+        );
+
+        let overflow = Datetime {
+            seconds: u64::MAX,
+            nanoseconds: 0,
+        };
+        assert_eq!(
+            SystemTime::try_from(overflow).err(),
+            Some(clocks::Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn datetime_rfc3339_round_trip() {
+        let cases = [
+            (0, 0, "1970-01-01T00:00:00Z"),
+            (951_782_400, 500_000_000, "2000-02-29T00:00:00.5Z"),
+            (1_234_567_890, 987_654_321, "2009-02-13T23:31:30.987654321Z"),
+            (253_402_300_799, 1_000, "9999-12-31T23:59:59.000001Z"),
+        ];
+        for (seconds, nanoseconds, text) in cases {
+            let datetime = Datetime {
+                seconds,
+                nanoseconds,
+            };
+            assert_eq!(datetime.to_rfc3339(), text);
+            let parsed = Datetime::from_rfc3339(text).unwrap();
+            assert_eq!((parsed.seconds, parsed.nanoseconds), (seconds, nanoseconds));
+        }
+    }
+
+    #[test]
+    fn datetime_from_rfc3339_variants() {
+        let parse = |s: &str| -> Result<(u64, u32), clocks::Error> {
+            let datetime = Datetime::from_rfc3339(s)?;
+            Ok((datetime.seconds, datetime.nanoseconds))
+        };
+        assert_eq!(parse("1970-01-01t01:00:00+01:00"), Ok((0, 0)));
+        assert_eq!(parse("1970-01-01T00:00:00-00:30"), Ok((1_800, 0)));
+        assert_eq!(
+            parse("1970-01-01T00:00:00.1234567899z"),
+            Ok((0, 123_456_789))
+        );
+        assert_eq!(
+            parse("1969-12-31T23:59:59Z"),
+            Err(clocks::Error::BeforeEpoch)
+        );
+        for invalid in [
+            "",
+            "1970-01-01",
+            "1970-01-01T00:00:00",
+            "1970-01-01T00:00:00.Z",
+            "1970-13-01T00:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "1970-01-01T24:00:00Z",
+            "1970-01-01T00:00:60Z",
+            "1970-01-01T00:00:00Zjunk",
+        ] {
+            assert_eq!(
+                parse(invalid),
+                Err(clocks::Error::InvalidFormat),
+                "{invalid}"
+            );
+        }
+    }
+
+    struct View {
+        table: crate::preview2::Table,
+        ctx: crate::preview2::WasiCtx,
+    }
+
+    impl WasiView for View {
+        fn table(&self) -> &crate::preview2::Table {
+            &self.table
+        }
+        fn table_mut(&mut self) -> &mut crate::preview2::Table {
+            &mut self.table
+        }
+        fn ctx(&self) -> &crate::preview2::WasiCtx {
+            &self.ctx
+        }
+        fn ctx_mut(&mut self) -> &mut crate::preview2::WasiCtx {
+            &mut self.ctx
+        }
+    }
+
+    fn view(builder: crate::preview2::WasiCtxBuilder) -> View {
+        let mut table = crate::preview2::Table::new();
+        let ctx = builder.build(&mut table).unwrap();
+        View { table, ctx }
+    }
+
+    #[tokio::test]
+    async fn subscribe_calls_the_hook() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook = seen.clone();
+        let mut view = view(
+            crate::preview2::WasiCtxBuilder::new()
+                .on_subscribe(move |when, absolute| hook.lock().unwrap().push((when, absolute))),
+        );
+        monotonic_clock::Host::subscribe(&mut view, 5, false)
+            .await
+            .unwrap();
+        monotonic_clock::Host::subscribe(&mut view, 9, true)
+            .await
+            .unwrap();
+        assert_eq!(*seen.lock().unwrap(), [(5, false), (9, true)]);
+    }
+
+    #[tokio::test]
+    async fn timezone_rejects_out_of_range_nanoseconds() {
+        let mut view = view(crate::preview2::WasiCtxBuilder::new());
+        let timezone = view
+            .table
+            .push(Box::new(clocks::Timezone::new("CET", 3600)))
+            .unwrap();
+        let at = |nanoseconds| Datetime {
+            seconds: 0,
+            nanoseconds,
+        };
+
+        assert_eq!(
+            timezone::Host::utc_offset(&mut view, timezone, at(999_999_999))
+                .await
+                .unwrap(),
+            3600
+        );
+        let err = timezone::Host::utc_offset(&mut view, timezone, at(1_000_000_000))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<clocks::Error>(),
+            Some(&clocks::Error::InvalidNanoseconds)
+        );
+
+        assert!(
+            timezone::Host::display(&mut view, timezone, at(999_999_999))
+                .await
+                .is_ok()
+        );
+        let err = timezone::Host::display(&mut view, timezone, at(1_000_000_000))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<clocks::Error>(),
+            Some(&clocks::Error::InvalidNanoseconds)
+        );
     }
 }
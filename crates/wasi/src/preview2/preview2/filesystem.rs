@@ -856,6 +856,20 @@ impl From<cap_rand::Error> for filesystem::Error {
     }
 }
 
+impl From<crate::preview2::clocks::Error> for filesystem::Error {
+    fn from(err: crate::preview2::clocks::Error) -> filesystem::Error {
+        use crate::preview2::clocks::Error;
+        match err {
+            Error::Overflow => ErrorCode::Overflow.into(),
+            Error::BeforeEpoch
+            | Error::InvalidNanoseconds
+            | Error::UnknownTimezone
+            | Error::InvalidFormat
+            | Error::InvalidTimezone => ErrorCode::Invalid.into(),
+        }
+    }
+}
+
 impl From<std::num::TryFromIntError> for filesystem::Error {
     fn from(_err: std::num::TryFromIntError) -> filesystem::Error {
         ErrorCode::Overflow.into()
@@ -893,10 +907,7 @@ fn systemtimespec_from(
 }
 
 fn systemtime_from(t: wall_clock::Datetime) -> Result<std::time::SystemTime, filesystem::Error> {
-    use std::time::{Duration, SystemTime};
-    SystemTime::UNIX_EPOCH
-        .checked_add(Duration::new(t.seconds, t.nanoseconds))
-        .ok_or_else(|| ErrorCode::Overflow.into())
+    Ok(cap_std::time::SystemTime::try_from(t)?.into_std())
 }
 
 fn datetime_from(t: std::time::SystemTime) -> wall_clock::Datetime {
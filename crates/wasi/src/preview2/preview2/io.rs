@@ -37,8 +37,14 @@ impl<T: WasiView> streams::Host for T {
     }
 
     async fn drop_output_stream(&mut self, stream: OutputStream) -> anyhow::Result<()> {
-        self.table_mut()
+        let mut s = self
+            .table_mut()
             .delete::<Box<dyn crate::preview2::OutputStream>>(stream)?;
+        // The guest is done with the stream either way, so a failure here
+        // isn't its fault and shouldn't trap it.
+        if let Err(err) = s.shutdown().await {
+            tracing::warn!("failed to shut down output stream: {err:?}");
+        }
         Ok(())
     }
 
@@ -57,6 +63,7 @@ impl<T: WasiView> streams::Host for T {
         let mut buffer = vec![0; buffer_len];
 
         let (bytes_read, end) = s.read(&mut buffer).await?;
+        self.charge_io(bytes_read).map_err(streams::Error::trap)?;
 
         buffer.truncate(bytes_read as usize);
 
@@ -73,10 +80,13 @@ impl<T: WasiView> streams::Host for T {
     }
 
     async fn write(&mut self, stream: OutputStream, bytes: Vec<u8>) -> Result<u64, streams::Error> {
+        let stream = self.resolve_output_stream(stream);
         let s: &mut Box<dyn crate::preview2::OutputStream> =
             self.table_mut().get_output_stream_mut(stream)?;
 
         let bytes_written: u64 = s.write(&bytes).await?;
+        self.charge_io(bytes_written)
+            .map_err(streams::Error::trap)?;
 
         Ok(u64::try_from(bytes_written).unwrap())
     }
@@ -95,6 +105,8 @@ impl<T: WasiView> streams::Host for T {
             self.table_mut().get_input_stream_mut(stream)?;
 
         let (bytes_skipped, end) = s.skip(len).await?;
+        self.charge_io(bytes_skipped)
+            .map_err(streams::Error::trap)?;
 
         Ok((bytes_skipped, end))
     }
@@ -113,10 +125,13 @@ impl<T: WasiView> streams::Host for T {
         stream: OutputStream,
         len: u64,
     ) -> Result<u64, streams::Error> {
+        let stream = self.resolve_output_stream(stream);
         let s: &mut Box<dyn crate::preview2::OutputStream> =
             self.table_mut().get_output_stream_mut(stream)?;
 
         let bytes_written: u64 = s.write_zeroes(len).await?;
+        self.charge_io(bytes_written)
+            .map_err(streams::Error::trap)?;
 
         Ok(bytes_written)
     }
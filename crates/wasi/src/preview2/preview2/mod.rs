@@ -3,5 +3,5 @@ mod env;
 mod exit;
 pub(crate) mod filesystem;
 mod io;
-mod poll;
+pub(crate) mod poll;
 mod random;
@@ -1,9 +1,10 @@
 use crate::preview2::{
+    sched::ReadinessScheduler,
     stream::TableStreamExt,
     wasi::clocks::monotonic_clock::Instant,
     wasi::io::streams::{InputStream, OutputStream},
     wasi::poll::poll::{self, Pollable},
-    WasiView,
+    Table, WasiView,
 };
 
 /// A pollable resource table entry.
@@ -21,6 +22,75 @@ pub(crate) enum PollableEntry {
     */
 }
 
+/// A description of a pollable in the table, for diagnosing guests that are
+/// stuck in `poll_oneoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollableInfo {
+    /// A pollable waiting for an input stream to be readable.
+    Read { pollable: Pollable, stream: u32 },
+    /// A pollable waiting for an output stream to be writable.
+    Write { pollable: Pollable, stream: u32 },
+    /// A pollable waiting for the monotonic clock to reach a deadline.
+    MonotonicClock {
+        pollable: Pollable,
+        when: Instant,
+        absolute: bool,
+    },
+}
+
+/// Counts kept over a context's calls to `poll_oneoff`, see
+/// [`WasiCtx::poll_stats`](crate::preview2::WasiCtx::poll_stats).
+///
+/// A guest whose calls consistently find only one pollable ready out of
+/// many subscribed may be rebuilding a large subscription list on every turn
+/// of its event loop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollStats {
+    /// The number of calls to `poll_oneoff`.
+    pub calls: u64,
+    /// The total number of pollables subscribed to, over all calls.
+    pub subscribed: u64,
+    /// The total number of pollables reported ready, over all calls.
+    pub ready: u64,
+}
+
+impl PollStats {
+    /// The fraction of subscribed pollables that were reported ready, or
+    /// `None` if none were subscribed.
+    pub fn ready_ratio(&self) -> Option<f64> {
+        if self.subscribed == 0 {
+            return None;
+        }
+        Some(self.ready as f64 / self.subscribed as f64)
+    }
+}
+
+pub trait TablePollableExt {
+    /// Describe every pollable currently in the table, in order of index.
+    fn debug_pollables(&self) -> Vec<PollableInfo>;
+}
+impl TablePollableExt for Table {
+    fn debug_pollables(&self) -> Vec<PollableInfo> {
+        self.iter_of::<PollableEntry>()
+            .map(|(pollable, entry)| PollableInfo::new(pollable, *entry))
+            .collect()
+    }
+}
+
+impl PollableInfo {
+    fn new(pollable: Pollable, entry: PollableEntry) -> Self {
+        match entry {
+            PollableEntry::Read(stream) => PollableInfo::Read { pollable, stream },
+            PollableEntry::Write(stream) => PollableInfo::Write { pollable, stream },
+            PollableEntry::MonotonicClock(when, absolute) => PollableInfo::MonotonicClock {
+                pollable,
+                when,
+                absolute,
+            },
+        }
+    }
+}
+
 // Implementatations of the interface. The bodies had been pulled out into
 // functions above to allow them to be shared between the two worlds, which
 // used to require different traits . Features have been added to facilitate
@@ -31,6 +101,7 @@ pub(crate) enum PollableEntry {
 impl<T: WasiView> poll::Host for T {
     async fn drop_pollable(&mut self, pollable: Pollable) -> anyhow::Result<()> {
         self.table_mut().delete::<PollableEntry>(pollable)?;
+        self.ctx_mut().pollable_labels.remove(&pollable);
         Ok(())
     }
 
@@ -40,16 +111,20 @@ impl<T: WasiView> poll::Host for T {
         // Convert `futures` into `Poll` subscriptions.
         let mut poll = Poll::new();
         let len = futures.len();
-        for (index, future) in futures.into_iter().enumerate() {
+        let mut entries = Vec::with_capacity(len);
+        for (index, &future) in futures.iter().enumerate() {
             let userdata = Userdata::from(index as u64);
 
-            match *self.table().get(future)? {
+            let entry = *self.table().get::<PollableEntry>(future)?;
+            entries.push(entry);
+            match entry {
                 PollableEntry::Read(stream) => {
                     let wasi_stream: &dyn crate::preview2::InputStream =
                         self.table().get_input_stream(stream)?;
                     poll.subscribe_read(wasi_stream, userdata);
                 }
                 PollableEntry::Write(stream) => {
+                    let stream = self.resolve_output_stream(stream);
                     let wasi_stream: &dyn crate::preview2::OutputStream =
                         self.table().get_output_stream(stream)?;
                     poll.subscribe_write(wasi_stream, userdata);
@@ -78,6 +153,98 @@ impl<T: WasiView> poll::Host for T {
         for (_result, data) in poll.results() {
             results[u64::from(data) as usize] = true;
         }
+        select_ready(&mut *self.ctx_mut().readiness_scheduler, &mut results);
+
+        let stats = &mut self.ctx_mut().poll_stats;
+        stats.calls += 1;
+        stats.subscribed += len as u64;
+        stats.ready += results.iter().filter(|ready| **ready).count() as u64;
+
+        for (index, ready) in results.iter().enumerate() {
+            if *ready {
+                let pollable = futures[index];
+                tracing::debug!(
+                    pollable,
+                    label = ?self.ctx().pollable_labels.get(&pollable),
+                    info = ?PollableInfo::new(pollable, entries[index]),
+                    "pollable ready"
+                );
+            }
+        }
         Ok(results)
     }
 }
+
+/// Let `scheduler` pick which of the `ready` pollables to report, reporting
+/// all of them if it wrongly clears every one.
+fn select_ready(scheduler: &mut dyn ReadinessScheduler, ready: &mut [bool]) {
+    let all = ready.to_vec();
+    scheduler.select(ready);
+    if all.contains(&true) && !ready.contains(&true) {
+        tracing::warn!("readiness scheduler cleared every ready pollable, reporting them all");
+        ready.copy_from_slice(&all);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_stats_ready_ratio() {
+        assert_eq!(PollStats::default().ready_ratio(), None);
+        let stats = PollStats {
+            calls: 2,
+            subscribed: 8,
+            ready: 2,
+        };
+        assert_eq!(stats.ready_ratio(), Some(0.25));
+    }
+
+    #[test]
+    fn debug_pollables() {
+        let mut table = Table::new();
+        let timer = table
+            .push(Box::new(PollableEntry::MonotonicClock(10, false)))
+            .unwrap();
+        let read = table.push(Box::new(PollableEntry::Read(0))).unwrap();
+        // Other resources in the table are not reported.
+        table.push(Box::new(0u32)).unwrap();
+        assert_eq!(
+            table.debug_pollables(),
+            vec![
+                PollableInfo::MonotonicClock {
+                    pollable: timer,
+                    when: 10,
+                    absolute: false,
+                },
+                PollableInfo::Read {
+                    pollable: read,
+                    stream: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_scheduler_cannot_hide_every_ready_pollable() {
+        struct ClearAll;
+
+        impl ReadinessScheduler for ClearAll {
+            fn select(&mut self, ready: &mut [bool]) {
+                ready.fill(false);
+            }
+        }
+
+        let mut ready = [false, true, true];
+        select_ready(&mut ClearAll, &mut ready);
+        assert_eq!(ready, [false, true, true]);
+
+        let mut ready = [false, true, true];
+        select_ready(
+            &mut crate::preview2::SeededReadinessScheduler::new(0),
+            &mut ready,
+        );
+        assert_eq!(ready.iter().filter(|ready| **ready).count(), 1);
+    }
+}
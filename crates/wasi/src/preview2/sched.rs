@@ -108,3 +108,106 @@ impl<'a> Poll<'a> {
         })
     }
 }
+
+/// Decides which of the pollables that are ready at the same time a call to
+/// `poll-oneoff` reports to the guest.
+///
+/// Which pollables are ready together depends on timing, so a guest polling
+/// several streams may take a different path on every run. Reporting fewer
+/// of them, in an order fixed by the scheduler, makes such guests
+/// reproducible in tests. Set one with
+/// [`WasiCtxBuilder::set_readiness_scheduler`](crate::preview2::WasiCtxBuilder::set_readiness_scheduler).
+pub trait ReadinessScheduler: Send + Sync {
+    /// Clear the entries of `ready`, which are set for the pollables that
+    /// are ready, that should not be reported this time. At least one entry
+    /// must be left set; the others will be reported by a later poll if
+    /// they are still ready. If every entry is cleared, all of the ready
+    /// pollables are reported, and a warning is logged.
+    fn select(&mut self, ready: &mut [bool]);
+}
+
+/// The default [`ReadinessScheduler`], which reports every ready pollable.
+pub struct ReportAllReady;
+
+impl ReadinessScheduler for ReportAllReady {
+    fn select(&mut self, _ready: &mut [bool]) {}
+}
+
+/// A [`ReadinessScheduler`] reporting one ready pollable at a time, picked at
+/// random. The randomness is seeded, so a run can be reproduced, and a test
+/// can cover different interleavings by trying several seeds.
+pub struct SeededReadinessScheduler {
+    rng: cap_rand::rngs::StdRng,
+}
+
+impl SeededReadinessScheduler {
+    /// Create a scheduler whose picks are determined by `seed`: the same seed
+    /// picks the same pollables from the same sequence of polls.
+    pub fn new(seed: u64) -> Self {
+        use cap_rand::SeedableRng;
+        Self {
+            rng: cap_rand::rngs::StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl ReadinessScheduler for SeededReadinessScheduler {
+    fn select(&mut self, ready: &mut [bool]) {
+        use cap_rand::Rng;
+        let count = ready.iter().filter(|ready| **ready).count();
+        if count == 0 {
+            return;
+        }
+        let mut keep = self.rng.gen_range(0..count);
+        for ready in ready.iter_mut().filter(|ready| **ready) {
+            *ready = keep == 0;
+            keep = keep.wrapping_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn picks(scheduler: &mut dyn ReadinessScheduler, ready: &[bool]) -> Vec<usize> {
+        let mut selected = ready.to_vec();
+        scheduler.select(&mut selected);
+        selected
+            .iter()
+            .enumerate()
+            .filter(|(_, ready)| **ready)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn report_all_ready_keeps_everything() {
+        assert_eq!(
+            picks(&mut ReportAllReady, &[true, false, true, true]),
+            [0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn seeded_scheduler_picks_one_reproducibly() {
+        let ready = [true, false, true, true, false, true];
+        let run = |seed| {
+            let mut scheduler = SeededReadinessScheduler::new(seed);
+            (0..100)
+                .map(|_| {
+                    let picked = picks(&mut scheduler, &ready);
+                    assert_eq!(picked.len(), 1);
+                    assert!(ready[picked[0]]);
+                    picked[0]
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(1));
+        // Every ready pollable gets its turn.
+        let seen = run(1);
+        for index in [0, 2, 3, 5] {
+            assert!(seen.contains(&index));
+        }
+    }
+}
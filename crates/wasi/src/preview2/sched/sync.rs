@@ -1,5 +1,5 @@
 use crate::preview2::sched::{
-    subscription::{RwEventFlags, RwStream},
+    subscription::{RwEventFlags, RwStream, RwSubscription},
     Poll, WasiSched,
 };
 use rustix::io::{PollFd, PollFlags};
@@ -8,12 +8,69 @@ use std::time::Duration;
 
 use anyhow::Error;
 
+/// Convert the time until a deadline, in nanoseconds, to a timeout in
+/// milliseconds for `poll`.
+///
+/// This rounds up, so that the deadline has passed once `poll` times out.
+/// A guest can ask for a deadline up to `u64::MAX` nanoseconds away, far more
+/// than `poll` can wait for, so the timeout is clamped and a longer wait
+/// takes several calls.
+///
+/// TODO: On Linux and FreeBSD, we could use `ppoll` instead which takes a
+/// `timespec`.
+fn poll_timeout(nanos: u64) -> i32 {
+    let millis = nanos / 1_000_000 + u64::from(nanos % 1_000_000 != 0);
+    i32::try_from(millis).unwrap_or(i32::MAX)
+}
+
+/// The longest `poll` waits for sockets between checks of stdin on Windows,
+/// in milliseconds.
+const STDIN_CHECK_INTERVAL: i32 = 10;
+
+/// Whether `rwsub` waits for input on the host's stdin.
+///
+/// Windows can't poll stdin, so a worker thread waits for it instead; see
+/// `stdio::poll_stdin`. Elsewhere stdin is polled like any other stream.
+#[cfg(windows)]
+fn is_stdin(rwsub: &RwSubscription) -> bool {
+    matches!(rwsub.stream, RwStream::Read(stream)
+        if stream.as_any().is::<crate::preview2::stdio::Stdin>())
+}
+
+#[cfg(not(windows))]
+fn is_stdin(_rwsub: &RwSubscription) -> bool {
+    false
+}
+
+/// Complete the stdin subscriptions in `poll` if stdin is ready, returning
+/// whether it was.
+#[cfg(windows)]
+fn complete_stdin(poll: &mut Poll, readiness: crate::preview2::stdio::StdinReadiness) -> bool {
+    use crate::preview2::stdio::StdinReadiness;
+
+    let result = match readiness {
+        StdinReadiness::Ready => Ok(()),
+        StdinReadiness::NotReady => return false,
+        StdinReadiness::Error(err) => Err(err),
+    };
+    for rwsub in poll.rw_subscriptions().filter(|rwsub| is_stdin(rwsub)) {
+        match &result {
+            Ok(()) => rwsub.complete(RwEventFlags::empty()),
+            Err(err) => rwsub.error(anyhow::anyhow!("stdin: {err}")),
+        }
+    }
+    true
+}
+
 pub(crate) async fn poll_oneoff<'a>(poll: &mut Poll<'a>) -> Result<(), Error> {
     // Collect all stream I/O subscriptions. Clock subscriptions are handled
-    // separately below.
+    // separately below, and so is stdin on Windows.
     let mut ready = false;
     let mut pollfds = Vec::new();
     for rwsub in poll.rw_subscriptions() {
+        if is_stdin(rwsub) {
+            continue;
+        }
         match rwsub.stream {
             RwStream::Read(stream) => {
                 // Poll things that can be polled.
@@ -58,8 +115,16 @@ pub(crate) async fn poll_oneoff<'a>(poll: &mut Poll<'a>) -> Result<(), Error> {
 
                 #[cfg(windows)]
                 {
+                    use crate::preview2::stdio::{Stderr, Stdout};
+
                     if let Some(fd) = fd.as_socket() {
                         pollfds.push(PollFd::from_borrowed_fd(fd, PollFlags::OUT));
+                    } else if stream.as_any().is::<Stdout>() || stream.as_any().is::<Stderr>() {
+                        // Console and pipe handles can't be polled, but
+                        // writes to stdout and stderr wait until they are
+                        // done, so they are always ready.
+                        rwsub.complete(RwEventFlags::empty());
+                        ready = true;
                     } else {
                         return Err(anyhow::anyhow!(
                             "unimplemented: polling for writing to non-OS resources"
@@ -75,28 +140,78 @@ pub(crate) async fn poll_oneoff<'a>(poll: &mut Poll<'a>) -> Result<(), Error> {
         }
     }
 
-    // If we didn't have any streams that are immediately available, do an OS
-    // `poll` to wait for streams to become available.
-    if !ready {
+    // On Windows, wait for stdin on its worker thread, up to the earliest
+    // timer. If sockets need polling too, only check it for now, and check it
+    // again between polls below.
+    let stdin_waiting = poll.rw_subscriptions().any(|rwsub| is_stdin(rwsub));
+    #[cfg(windows)]
+    {
+        if stdin_waiting {
+            let timeout = if ready || !pollfds.is_empty() {
+                Some(Duration::ZERO)
+            } else {
+                poll.earliest_clock_deadline().map(|t| {
+                    Duration::from_nanos(t.absolute_deadline.saturating_sub(t.clock.now()))
+                })
+            };
+            ready |= complete_stdin(poll, crate::preview2::stdio::poll_stdin(timeout));
+        }
+    }
+
+    // Do an OS `poll` to find which host streams are ready. If some streams
+    // were immediately available, only check which are ready right now
+    // rather than waiting, so that every subscription that is ready gets
+    // reported and not just the first. Otherwise wait for streams to become
+    // available, or for the earliest timer.
+    if !ready || !pollfds.is_empty() {
         loop {
-            let poll_timeout = if let Some(t) = poll.earliest_clock_deadline() {
-                // Convert the timeout to milliseconds for `poll`, rounding up.
-                //
-                // TODO: On Linux and FreeBSD, we could use `ppoll` instead
-                // which takes a `timespec.`
-                ((t.absolute_deadline.saturating_sub(t.clock.now()) + 999_999) / 1_000_000)
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("overflow: poll timeout"))?
+            let poll_timeout = if ready {
+                0
+            } else if let Some(t) = poll.earliest_clock_deadline() {
+                poll_timeout(t.absolute_deadline.saturating_sub(t.clock.now()))
             } else {
                 // A negative value requests an infinite timeout.
                 -1
             };
+            let poll_timeout = if stdin_waiting && !ready {
+                if (0..STDIN_CHECK_INTERVAL).contains(&poll_timeout) {
+                    poll_timeout
+                } else {
+                    STDIN_CHECK_INTERVAL
+                }
+            } else {
+                poll_timeout
+            };
             tracing::debug!(
                 poll_timeout = tracing::field::debug(poll_timeout),
                 poll_fds = tracing::field::debug(&pollfds),
                 "poll"
             );
             match rustix::io::poll(&mut pollfds, poll_timeout) {
+                Ok(0) => {
+                    #[cfg(windows)]
+                    {
+                        if stdin_waiting && !ready {
+                            let stdin = crate::preview2::stdio::poll_stdin(Some(Duration::ZERO));
+                            if complete_stdin(poll, stdin) {
+                                ready = true;
+                                break;
+                            }
+                        }
+                    }
+                    // Nothing became ready in time. If the timeout was
+                    // clamped, the deadline may still be ahead, so keep
+                    // waiting for it. Without a deadline, the timeout was
+                    // only clamped to check stdin.
+                    let keep_waiting = match poll.earliest_clock_deadline() {
+                        Some(t) => t.result().is_none(),
+                        None => stdin_waiting,
+                    };
+                    if !ready && keep_waiting {
+                        continue;
+                    }
+                    break;
+                }
                 Ok(_num_ready) => {
                     ready = true;
                     break;
@@ -106,26 +221,34 @@ pub(crate) async fn poll_oneoff<'a>(poll: &mut Poll<'a>) -> Result<(), Error> {
             }
         }
 
-        assert_eq!(poll.rw_subscriptions().count(), pollfds.len());
-
-        // If the OS `poll` returned events, record them.
-        if ready {
-            // Iterate through the stream subscriptions, skipping those that
-            // were already completed due to being immediately available.
-            for (rwsub, pollfd) in poll.rw_subscriptions().zip(pollfds.into_iter()) {
-                let revents = pollfd.revents();
-                if revents.contains(PollFlags::NVAL) {
-                    rwsub.error(anyhow::anyhow!("rw subscription badf"));
-                } else if revents.contains(PollFlags::ERR) {
-                    rwsub.error(anyhow::anyhow!("rw subscription io error"));
-                } else if revents.contains(PollFlags::HUP) {
-                    rwsub.complete(RwEventFlags::HANGUP);
-                } else {
-                    rwsub.complete(RwEventFlags::empty());
-                };
-            }
+        assert_eq!(
+            poll.rw_subscriptions()
+                .filter(|rwsub| !rwsub.is_complete() && !is_stdin(rwsub))
+                .count(),
+            pollfds.len()
+        );
+
+        // Record the events, skipping the subscriptions that were already
+        // completed due to being immediately available, and the ones whose
+        // streams are not ready.
+        let pending = poll
+            .rw_subscriptions()
+            .filter(|rwsub| !rwsub.is_complete() && !is_stdin(rwsub));
+        for (rwsub, pollfd) in pending.zip(pollfds.into_iter()) {
+            let revents = pollfd.revents();
+            if revents.is_empty() {
+                continue;
+            } else if revents.contains(PollFlags::NVAL) {
+                rwsub.error(anyhow::anyhow!("rw subscription badf"));
+            } else if revents.contains(PollFlags::ERR) {
+                rwsub.error(anyhow::anyhow!("rw subscription io error"));
+            } else if revents.contains(PollFlags::HUP) {
+                rwsub.complete(RwEventFlags::HANGUP);
+            } else {
+                rwsub.complete(RwEventFlags::empty());
+            };
         }
-    };
+    }
 
     // If we had no immediately-available events and no events becoming
     // available in a `poll`, it means we timed out. Report that event.
@@ -154,3 +277,141 @@ impl WasiSched for SyncSched {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+    use crate::preview2::sched::SubscriptionResult;
+    #[cfg(unix)]
+    use crate::preview2::stream::InputStream;
+
+    #[test]
+    fn poll_timeout_rounds_up_and_clamps() {
+        assert_eq!(poll_timeout(0), 0);
+        assert_eq!(poll_timeout(1), 1);
+        assert_eq!(poll_timeout(1_000_000), 1);
+        assert_eq!(poll_timeout(1_000_001), 2);
+        assert_eq!(poll_timeout(u64::MAX), i32::MAX);
+    }
+
+    /// Poll a single timer, returning whether it fired.
+    async fn timer_fires(clock: &TickClock, when: u64, absolute: bool) -> bool {
+        let mut poll = Poll::new();
+        poll.subscribe_monotonic_clock(clock, when, absolute, 0.into());
+        poll_oneoff(&mut poll).await.unwrap();
+        poll.results()
+            .any(|(result, _)| matches!(result, SubscriptionResult::MonotonicClock(Ok(()))))
+    }
+
+    #[tokio::test]
+    async fn immediate_deadlines_resolve() {
+        let clock = TickClock::new(1_000);
+        clock.tick();
+        // A relative deadline of zero is now.
+        assert!(timer_fires(&clock, 0, false).await);
+        // An absolute deadline in the past has already passed.
+        assert!(timer_fires(&clock, 1, true).await);
+    }
+
+    #[test]
+    fn huge_deadline_does_not_overflow() {
+        let clock = TickClock::new(1_000);
+        clock.tick();
+        let mut poll = Poll::new();
+        poll.subscribe_monotonic_clock(&clock, u64::MAX, false, 0.into());
+        let t = poll.earliest_clock_deadline().unwrap();
+        assert_eq!(t.absolute_deadline, u64::MAX);
+        assert!(t.result().is_none());
+        assert_eq!(
+            poll_timeout(t.absolute_deadline.saturating_sub(t.now())),
+            i32::MAX
+        );
+    }
+
+    /// An input stream that is only ever polled, through a host socket.
+    #[cfg(unix)]
+    struct Socket<T>(T);
+
+    #[cfg(unix)]
+    #[async_trait::async_trait]
+    impl<T: rustix::fd::AsFd + Send + Sync + 'static> InputStream for Socket<T> {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+
+        fn pollable_read(&self) -> Option<rustix::fd::BorrowedFd> {
+            Some(self.0.as_fd())
+        }
+
+        async fn readable(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Run `poll`, returning the userdata of the subscriptions it reported.
+    #[cfg(unix)]
+    async fn ready_userdata(mut poll: Poll<'_>) -> Vec<u64> {
+        poll_oneoff(&mut poll).await.unwrap();
+        poll.results()
+            .map(|(result, ud)| {
+                assert!(!matches!(
+                    result,
+                    SubscriptionResult::ReadWrite(Err(_))
+                        | SubscriptionResult::MonotonicClock(Err(_))
+                ));
+                ud.into()
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn timer_fires_beside_a_stream_that_is_never_ready() {
+        use crate::preview2::clocks::host::MonotonicClock;
+        use std::time::Instant;
+
+        // Nothing ever connects to the listener, so it never becomes readable.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let idle = Socket(listener);
+        let clock = MonotonicClock::new(cap_std::ambient_authority());
+
+        let start = Instant::now();
+        let mut poll = Poll::new();
+        poll.subscribe_read(&idle, 1.into());
+        poll.subscribe_monotonic_clock(&clock, 50_000_000, false, 2.into());
+        assert_eq!(ready_userdata(poll).await, [2]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn every_ready_subscription_is_reported() {
+        use crate::preview2::pipe::ReadPipe;
+        use std::io::Write;
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(b"x").unwrap();
+        let readable = Socket(server);
+        let idle = Socket(listener);
+        let in_memory = ReadPipe::from("ready");
+        let clock = TickClock::new(0);
+
+        // Wait for the byte to arrive, so that the socket is ready when it
+        // is checked without blocking below.
+        let mut poll = Poll::new();
+        poll.subscribe_read(&readable, 0.into());
+        assert_eq!(ready_userdata(poll).await, [0]);
+
+        let mut poll = Poll::new();
+        poll.subscribe_read(&in_memory, 1.into());
+        poll.subscribe_read(&idle, 2.into());
+        poll.subscribe_read(&readable, 3.into());
+        poll.subscribe_monotonic_clock(&clock, 0, true, 4.into());
+        poll.subscribe_monotonic_clock(&clock, 1_000, true, 5.into());
+        assert_eq!(ready_userdata(poll).await, [1, 3, 4]);
+    }
+}
@@ -0,0 +1,772 @@
+//! Shaping the writes that reach an output stream.
+//!
+//! The wrappers in this module change how a guest's writes are passed on to
+//! an inner stream, without changing the bytes themselves: in fixed-size
+//! frames, coalesced into larger writes, cut off or padded to a fixed size,
+//! limited to a few writers at once, or only ever whole.
+
+use crate::preview2::clocks::WasiMonotonicClock;
+use crate::preview2::stream::{forward_output_stream, OutputStream};
+use anyhow::Error;
+use std::any::Any;
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// What a [`FramingOutputStream`] does with an incomplete final frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialFrame {
+    /// Fill the frame up with the given byte and forward it.
+    Pad(u8),
+    /// Drop the incomplete frame.
+    Discard,
+    /// Fail with an error.
+    Error,
+}
+
+/// An output stream that forwards bytes to an inner stream only in complete
+/// frames of a fixed size.
+///
+/// Bytes are held until a whole frame is available, and each frame is written
+/// to the inner stream before any more bytes are accepted, so the inner stream
+/// only ever sees frame-aligned data. Call [`finish`](Self::finish) once the
+/// guest is done writing to deal with an incomplete final frame as configured;
+/// [`shutdown`](OutputStream::shutdown) also does this.
+pub struct FramingOutputStream<T> {
+    inner: T,
+    frame_size: usize,
+    on_partial: PartialFrame,
+    /// The frame being assembled or written out.
+    frame: Vec<u8>,
+    /// How much of a complete `frame` the inner stream has accepted so far.
+    written: usize,
+}
+
+impl<T: OutputStream> FramingOutputStream<T> {
+    /// # Panics
+    ///
+    /// Panics if `frame_size` is zero.
+    pub fn new(inner: T, frame_size: usize, on_partial: PartialFrame) -> Self {
+        assert!(frame_size > 0, "frame size must be nonzero");
+        Self {
+            inner,
+            frame_size,
+            on_partial,
+            frame: Vec::with_capacity(frame_size),
+            written: 0,
+        }
+    }
+
+    /// Write out the current frame if it is complete, returning whether the
+    /// frame buffer is now free for more bytes.
+    async fn write_frame(&mut self) -> Result<bool, Error> {
+        if self.frame.len() < self.frame_size {
+            return Ok(true);
+        }
+        while self.written < self.frame.len() {
+            let n = self.inner.write(&self.frame[self.written..]).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            self.written += usize::try_from(n)?;
+        }
+        self.frame.clear();
+        self.written = 0;
+        Ok(true)
+    }
+
+    /// Write out any complete frame still held, then handle an incomplete
+    /// final frame according to the configured [`PartialFrame`] policy.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        if !self.write_frame().await? {
+            return Err(anyhow::anyhow!("frame could not be written"));
+        }
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        match self.on_partial {
+            PartialFrame::Pad(byte) => {
+                self.frame.resize(self.frame_size, byte);
+                if !self.write_frame().await? {
+                    return Err(anyhow::anyhow!("frame could not be written"));
+                }
+            }
+            PartialFrame::Discard => self.frame.clear(),
+            PartialFrame::Error => {
+                return Err(anyhow::anyhow!(
+                    "incomplete frame of {} bytes",
+                    self.frame.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for FramingOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let mut accepted = 0;
+        while self.write_frame().await? && accepted < buf.len() {
+            let n = (buf.len() - accepted).min(self.frame_size - self.frame.len());
+            self.frame.extend_from_slice(&buf[accepted..][..n]);
+            accepted += n;
+        }
+        Ok(accepted.try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.finish().await?;
+        self.inner.shutdown().await
+    }
+}
+
+/// An output stream that coalesces small writes into larger ones, e.g. to
+/// avoid sending a network packet per guest write.
+///
+/// Writes are buffered and passed on to the inner stream together once
+/// either [`flush_after_bytes`](Self::flush_after_bytes) bytes are buffered
+/// or the oldest buffered byte has waited for
+/// [`flush_after`](Self::flush_after) on the given clock, whichever comes
+/// first. A write at least as large as the byte threshold goes straight
+/// through when nothing is buffered.
+///
+/// The time threshold is checked when the guest writes, so output from a
+/// guest that has gone quiet stays buffered until it writes again, the
+/// stream is synced or shut down, or the host calls
+/// [`flush_if_due`](Self::flush_if_due).
+pub struct CoalescingOutputStream<T> {
+    inner: T,
+    clock: Box<dyn WasiMonotonicClock>,
+    buffer: Vec<u8>,
+    /// When the oldest byte in `buffer` was written, on `clock`.
+    buffered_since: Option<u64>,
+    flush_after_bytes: usize,
+    flush_after: Option<u64>,
+}
+
+/// The default [`CoalescingOutputStream::flush_after_bytes`].
+pub const DEFAULT_FLUSH_AFTER_BYTES: usize = 8 * 1024;
+
+impl<T: OutputStream> CoalescingOutputStream<T> {
+    /// Create a stream that flushes after [`DEFAULT_FLUSH_AFTER_BYTES`] bytes,
+    /// with no time threshold.
+    pub fn new(inner: T, clock: impl WasiMonotonicClock + 'static) -> Self {
+        Self {
+            inner,
+            clock: Box::new(clock),
+            buffer: Vec::new(),
+            buffered_since: None,
+            flush_after_bytes: DEFAULT_FLUSH_AFTER_BYTES,
+            flush_after: None,
+        }
+    }
+
+    /// Set the number of buffered bytes that triggers a flush. This is also
+    /// the most that is ever buffered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero.
+    pub fn flush_after_bytes(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "flush threshold must be nonzero");
+        self.flush_after_bytes = bytes;
+        self
+    }
+
+    /// Set how long buffered bytes may wait before a flush is triggered.
+    pub fn flush_after(mut self, delay: Duration) -> Self {
+        self.flush_after = Some(delay.as_nanos().try_into().unwrap_or(u64::MAX));
+        self
+    }
+
+    /// Whether either threshold has been crossed.
+    fn is_due(&self) -> bool {
+        if self.buffer.len() >= self.flush_after_bytes {
+            return true;
+        }
+        match (self.buffered_since, self.flush_after) {
+            (Some(since), Some(delay)) => self.clock.now().saturating_sub(since) >= delay,
+            _ => false,
+        }
+    }
+
+    /// Pass as much of the buffer to the inner stream as it accepts.
+    async fn drain(&mut self) -> Result<(), Error> {
+        while !self.buffer.is_empty() {
+            let n = self.inner.write(&self.buffer).await?;
+            if n == 0 {
+                break;
+            }
+            self.buffer.drain(..usize::try_from(n)?);
+        }
+        if self.buffer.is_empty() {
+            self.buffered_since = None;
+        }
+        Ok(())
+    }
+
+    /// Flush the buffer if either threshold has been crossed.
+    pub async fn flush_if_due(&mut self) -> Result<(), Error> {
+        if self.is_due() {
+            self.drain().await?;
+        }
+        Ok(())
+    }
+
+    /// Pass all buffered bytes to the inner stream, failing if it does not
+    /// accept them all.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.drain().await?;
+        if !self.buffer.is_empty() {
+            anyhow::bail!(
+                "inner stream did not accept {} buffered bytes",
+                self.buffer.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for CoalescingOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        self.flush_if_due().await?;
+        if self.buffer.is_empty() && buf.len() >= self.flush_after_bytes {
+            return self.inner.write(buf).await;
+        }
+        let n = buf
+            .len()
+            .min(self.flush_after_bytes.saturating_sub(self.buffer.len()));
+        if n != 0 && self.buffer.is_empty() {
+            self.buffered_since = Some(self.clock.now());
+        }
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.flush_if_due().await?;
+        Ok(n.try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.sync_all().await
+    }
+}
+
+/// An output stream that makes an inner stream receive exactly `size` bytes,
+/// e.g. to produce a fixed-size disk image whatever the guest writes.
+///
+/// Guest writes are forwarded until `size` bytes have been written; anything
+/// past that is dropped, though the write still reports it as written so the
+/// guest carries on. If the guest wrote fewer than `size` bytes, shutting the
+/// stream down fills the rest with `pad_byte` before shutting the inner
+/// stream down, failing if the inner stream stops accepting the padding.
+pub struct FixedSizeOutputStream<T> {
+    inner: T,
+    size: u64,
+    pad_byte: u8,
+    /// The number of bytes the inner stream has accepted.
+    written: u64,
+}
+
+impl<T: OutputStream> FixedSizeOutputStream<T> {
+    /// Create a stream that gives `inner` exactly `size` bytes, padding a
+    /// short output with `pad_byte`.
+    pub fn new(inner: T, size: u64, pad_byte: u8) -> Self {
+        Self {
+            inner,
+            size,
+            pad_byte,
+            written: 0,
+        }
+    }
+
+    /// Write `pad_byte` to the inner stream until it has `size` bytes.
+    async fn pad(&mut self) -> Result<(), Error> {
+        let padding = [self.pad_byte; 4096];
+        while self.written < self.size {
+            let len = usize::try_from(self.size - self.written)
+                .unwrap_or(usize::MAX)
+                .min(padding.len());
+            let n = self.inner.write(&padding[..len]).await?;
+            if n == 0 {
+                anyhow::bail!(
+                    "inner stream did not accept {} bytes of padding",
+                    self.size - self.written
+                );
+            }
+            self.written += n;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for FixedSizeOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let room = usize::try_from(self.size - self.written).unwrap_or(usize::MAX);
+        let len = buf.len().min(room);
+        if len == 0 {
+            return Ok(buf.len().try_into()?);
+        }
+        let n = self.inner.write(&buf[..len]).await?;
+        self.written += n;
+        if n < u64::try_from(len)? {
+            return Ok(n);
+        }
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.pad().await?;
+        self.inner.shutdown().await
+    }
+}
+
+/// A counting semaphore limiting how many [`SemaphoreLimitedOutputStream`]s
+/// write at once.
+///
+/// This is implemented here rather than taken from an async runtime, so that
+/// it works under whichever runtime the embedding uses. Clones share the same
+/// permits.
+#[derive(Clone, Debug)]
+pub struct Semaphore {
+    state: Arc<Mutex<SemaphoreState>>,
+}
+
+#[derive(Debug)]
+struct SemaphoreState {
+    available: usize,
+    /// Tasks waiting for a permit.
+    wakers: Vec<Waker>,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `permits` permits.
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SemaphoreState {
+                available: permits,
+                wakers: Vec::new(),
+            })),
+        }
+    }
+
+    /// The number of permits not currently held.
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().available
+    }
+
+    /// Wait for a permit, which is given back when the returned guard is
+    /// dropped.
+    pub fn acquire(&self) -> impl Future<Output = Permit> + Send + 'static {
+        Acquire {
+            semaphore: self.clone(),
+        }
+    }
+}
+
+/// The future returned by [`Semaphore::acquire`].
+struct Acquire {
+    semaphore: Semaphore,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let mut state = self.semaphore.state.lock().unwrap();
+        if state.available == 0 {
+            state.wakers.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state.available -= 1;
+        Poll::Ready(Permit {
+            semaphore: self.semaphore.clone(),
+        })
+    }
+}
+
+/// A permit from a [`Semaphore`], given back when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    semaphore: Semaphore,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.semaphore.state.lock() {
+            state.available += 1;
+            // Wake every waiter rather than one, since a waiter may have been
+            // cancelled and would never take its turn.
+            for waker in state.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An output stream that holds a permit from a shared [`Semaphore`] while
+/// it writes, so that only as many streams as there are permits write to
+/// their inner streams at once, e.g. to limit concurrent writers to a disk.
+///
+/// A write waits for a permit and gives it back once the inner stream
+/// returns, whether it succeeded or failed, so an error never leaks a
+/// permit. Permits are handed out in no particular order, and one stream
+/// whose inner stream is slow holds its permit for as long as each write
+/// takes, delaying every stream waiting behind it. Syncing and shutting
+/// down also take a permit, since they may write out buffered data.
+pub struct SemaphoreLimitedOutputStream<T> {
+    inner: T,
+    semaphore: Semaphore,
+}
+
+impl<T: OutputStream> SemaphoreLimitedOutputStream<T> {
+    /// Create a stream that writes to `inner` only while holding a permit
+    /// from `semaphore`, which is shared with the other limited streams.
+    pub fn new(inner: T, semaphore: Semaphore) -> Self {
+        Self { inner, semaphore }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for SemaphoreLimitedOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let _permit = self.semaphore.acquire().await;
+        self.inner.write(buf).await
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        let _permit = self.semaphore.acquire().await;
+        self.inner.write_zeroes(nelem).await
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        let _permit = self.semaphore.acquire().await;
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        let _permit = self.semaphore.acquire().await;
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        let _permit = self.semaphore.acquire().await;
+        self.inner.sync_all().await
+    }
+}
+
+/// An output stream that accepts each guest write of up to a configured size
+/// whole or not at all, so that small discrete messages are never split by a
+/// partial write.
+///
+/// Each such message is handed to the inner stream in a single write. If the
+/// inner stream does not take all of it, the rest is held and the write still
+/// reports the whole message as written; further writes then accept nothing
+/// until the held bytes have been passed on, which happens on the next write
+/// once the inner stream is writable again. An inner stream that accepts
+/// whole writes or nothing, like a [`pipe`](crate::preview2::pipe::pipe), therefore receives every message
+/// in one piece, while one that takes partial writes, like a
+/// [`byte_bounded_pipe`](crate::preview2::pipe::byte_bounded_pipe), may still receive a message in parts. Writes larger
+/// than the limit go straight through and may be partially accepted as usual.
+pub struct AtomicWriteOutputStream<T> {
+    inner: T,
+    max_message: usize,
+    /// The part of the last message that the inner stream has not taken yet.
+    pending: Vec<u8>,
+}
+
+impl<T: OutputStream> AtomicWriteOutputStream<T> {
+    /// Create a stream that writes messages of up to `max_message` bytes
+    /// atomically.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_message` is zero.
+    pub fn new(inner: T, max_message: usize) -> Self {
+        assert!(max_message > 0, "atomic write limit must be nonzero");
+        Self {
+            inner,
+            max_message,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Pass as much of the held message to the inner stream as it accepts.
+    async fn drain(&mut self) -> Result<(), Error> {
+        while !self.pending.is_empty() {
+            let n = self.inner.write(&self.pending).await?;
+            if n == 0 {
+                break;
+            }
+            self.pending.drain(..usize::try_from(n)?);
+        }
+        Ok(())
+    }
+
+    /// Pass the held message to the inner stream, failing if it does not
+    /// accept all of it.
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.drain().await?;
+        if !self.pending.is_empty() {
+            anyhow::bail!(
+                "inner stream did not accept {} buffered bytes",
+                self.pending.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for AtomicWriteOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        self.drain().await?;
+        if !self.pending.is_empty() {
+            return Ok(0);
+        }
+        if buf.len() > self.max_message {
+            return self.inner.write(buf).await;
+        }
+        let n = usize::try_from(self.inner.write(buf).await?)?;
+        self.pending.extend_from_slice(&buf[n..]);
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.sync_all().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+    use crate::preview2::pipe::{byte_bounded_pipe, pipe, WritePipe};
+
+    #[tokio::test]
+    async fn framing_output_stream() {
+        let (mut input, output) = pipe(16);
+        let mut stream = FramingOutputStream::new(output, 4, PartialFrame::Pad(b'.'));
+        assert_eq!(stream.write(b"abcdef").await.unwrap(), 6);
+        assert_eq!(stream.write(b"gh").await.unwrap(), 2);
+        assert_eq!(stream.write(b"i").await.unwrap(), 1);
+        stream.finish().await.unwrap();
+
+        let mut frames = Vec::new();
+        let mut buf = [0; 16];
+        loop {
+            let (n, _) = input.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            frames.push(buf[..n as usize].to_vec());
+        }
+        assert_eq!(frames, [b"abcd", b"efgh", b"i..."]);
+
+        let (_input, output) = pipe(16);
+        let mut stream = FramingOutputStream::new(output, 4, PartialFrame::Error);
+        stream.write(b"abc").await.unwrap();
+        assert!(stream.finish().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn coalescing_output_stream_flushes_on_either_threshold() {
+        let clock = TickClock::new(0);
+        let capture = WritePipe::new_in_memory();
+        let mut stream = CoalescingOutputStream::new(capture.clone(), clock.clone())
+            .flush_after_bytes(4)
+            .flush_after(Duration::from_nanos(100));
+
+        // The byte threshold.
+        assert_eq!(stream.write(b"ab").await.unwrap(), 2);
+        assert_eq!(capture.take_contents(), b"");
+        assert_eq!(stream.write(b"cdef").await.unwrap(), 2);
+        assert_eq!(capture.take_contents(), b"abcd");
+
+        // The time threshold, counted from the oldest buffered byte.
+        clock.set(50);
+        assert_eq!(stream.write(b"e").await.unwrap(), 1);
+        clock.set(100);
+        stream.flush_if_due().await.unwrap();
+        assert_eq!(capture.take_contents(), b"");
+        clock.set(150);
+        stream.flush_if_due().await.unwrap();
+        assert_eq!(capture.take_contents(), b"e");
+
+        // Large writes go straight through, and shutting down flushes the rest.
+        assert_eq!(stream.write(b"ghijkl").await.unwrap(), 6);
+        assert_eq!(capture.take_contents(), b"ghijkl");
+        stream.write(b"m").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(capture.take_contents(), b"m");
+    }
+
+    #[tokio::test]
+    async fn semaphore_limited_writes_wait_for_a_permit() {
+        let semaphore = Semaphore::new(1);
+        let capture = WritePipe::new_in_memory();
+        let mut stream = SemaphoreLimitedOutputStream::new(capture.clone(), semaphore.clone());
+
+        let permit = semaphore.acquire().await;
+        assert_eq!(semaphore.available(), 0);
+        {
+            let mut write = stream.write(b"waits");
+            let poll = std::future::poll_fn(|cx| Poll::Ready(write.as_mut().poll(cx))).await;
+            assert!(poll.is_pending());
+        }
+        assert_eq!(capture.take_contents(), b"");
+
+        drop(permit);
+        assert_eq!(stream.write(b"goes").await.unwrap(), 4);
+        assert_eq!(capture.take_contents(), b"goes");
+        assert_eq!(semaphore.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn atomic_write_output_stream_never_splits_messages() {
+        let (mut input, output) = pipe(1);
+        let mut output = AtomicWriteOutputStream::new(output, 8);
+        let mut buf = [0; 16];
+
+        assert_eq!(output.write(b"one").await.unwrap(), 3);
+        // The pipe is full, so the whole message is held.
+        assert_eq!(output.write(b"two").await.unwrap(), 3);
+        // Nothing more is accepted while a message is held.
+        assert_eq!(output.write(b"three").await.unwrap(), 0);
+
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(output.write(b"three").await.unwrap(), 5);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf[..3], b"two");
+
+        output.shutdown().await.unwrap();
+        assert_eq!(input.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"three");
+    }
+
+    #[tokio::test]
+    async fn atomic_write_output_stream_passes_large_writes_through() {
+        let (_input, output) = byte_bounded_pipe(4);
+        let mut output = AtomicWriteOutputStream::new(output, 2);
+        assert_eq!(output.write(b"abcdef").await.unwrap(), 4);
+        assert_eq!(output.write(b"gh").await.unwrap(), 2);
+        // The held message can't be passed on, so shutting down fails.
+        assert!(output.shutdown().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fixed_size_output_stream_truncates() {
+        let sink = WritePipe::new_in_memory();
+        let mut output = FixedSizeOutputStream::new(sink.clone(), 8, 0);
+        assert_eq!(output.write(b"hello").await.unwrap(), 5);
+        assert_eq!(output.write(b", world").await.unwrap(), 7);
+        assert_eq!(output.write(b"!").await.unwrap(), 1);
+        output.shutdown().await.unwrap();
+        assert_eq!(sink.contents(), b"hello, w");
+    }
+
+    #[tokio::test]
+    async fn fixed_size_output_stream_pads() {
+        let sink = WritePipe::new_in_memory();
+        let mut output = FixedSizeOutputStream::new(sink.clone(), 6000, b'.');
+        output.write(b"abc").await.unwrap();
+        output.shutdown().await.unwrap();
+        let contents = sink.contents();
+        assert_eq!(contents.len(), 6000);
+        assert_eq!(&contents[..3], b"abc");
+        assert!(contents[3..].iter().all(|b| *b == b'.'));
+
+        let (_input, output) = byte_bounded_pipe(4);
+        let mut output = FixedSizeOutputStream::new(output, 8, 0);
+        assert!(output.shutdown().await.is_err());
+    }
+}
@@ -4,6 +4,7 @@ use std::convert::TryInto;
 use std::io::{self, Read, Write};
 use system_interface::io::ReadReady;
 
+use crate::preview2::stream::yield_now;
 use crate::preview2::{InputStream, OutputStream};
 #[cfg(unix)]
 use cap_std::io_lifetimes::{AsFd, BorrowedFd};
@@ -12,10 +13,148 @@ use cap_std::io_lifetimes::{AsHandle, BorrowedHandle};
 #[cfg(windows)]
 use io_extras::os::windows::{AsHandleOrSocket, BorrowedHandleOrSocket};
 
-pub struct Stdin(std::io::Stdin);
+/// Read into `bufs`, reporting the end of the stream only when a read that
+/// had room for data returned none.
+///
+/// A read that fills part of `bufs` before reaching the end of the input
+/// reports only the bytes delivered; the end is reported by the following
+/// read. Empty `bufs` can't receive data, so reading into them is never
+/// taken to mean the end of the stream.
+fn read_vectored(r: &mut impl Read, bufs: &mut [io::IoSliceMut<'_>]) -> Result<(u64, bool), Error> {
+    if bufs.iter().all(|buf| buf.is_empty()) {
+        return Ok((0, false));
+    }
+    match Read::read_vectored(r, bufs) {
+        Ok(0) => Ok((0, true)),
+        Ok(n) => Ok((n.try_into()?, false)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+            ) =>
+        {
+            Ok((0, false))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Read and discard up to `nelem` bytes, stopping early at the end of the
+/// input or, if `r` is non-blocking, when no more input is available yet.
+fn skip_input(r: &mut impl Read, nelem: u64) -> io::Result<(u64, bool)> {
+    let mut buf = [0; 8192];
+    let mut skipped = 0;
+    while skipped < nelem {
+        let len = (nelem - skipped).min(buf.len() as u64) as usize;
+        match Read::read(r, &mut buf[..len]) {
+            Ok(0) => return Ok((skipped, true)),
+            Ok(n) => skipped += n as u64,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((skipped, false))
+}
+
+#[cfg(unix)]
+fn fd_is_nonblocking(fd: BorrowedFd<'_>) -> io::Result<bool> {
+    use rustix::fs::{fcntl_getfl, OFlags};
+    Ok(fcntl_getfl(fd)?.contains(OFlags::NONBLOCK))
+}
+
+#[cfg(unix)]
+fn fd_set_nonblocking(fd: BorrowedFd<'_>, nonblocking: bool) -> io::Result<()> {
+    use rustix::fs::{fcntl_getfl, fcntl_setfl, OFlags};
+    let mut flags = fcntl_getfl(fd)?;
+    flags.set(OFlags::NONBLOCK, nonblocking);
+    fcntl_setfl(fd, flags)?;
+    Ok(())
+}
+
+/// The host process's standard input, as a guest input stream.
+///
+/// On Unix, the second field holds the terminal attributes saved when raw
+/// mode is entered, to be restored when it is left.
+pub struct Stdin(
+    std::io::Stdin,
+    #[cfg(unix)] Option<rustix::termios::Termios>,
+);
 
 pub fn stdin() -> Stdin {
-    Stdin(std::io::stdin())
+    Stdin(
+        std::io::stdin(),
+        #[cfg(unix)]
+        None,
+    )
+}
+
+/// Switch the terminal `fd` into raw mode, saving its attributes in
+/// `cooked`, or back to the attributes saved there.
+///
+/// This is a no-op if `fd` is not a terminal.
+#[cfg(unix)]
+fn set_terminal_raw_mode(
+    fd: BorrowedFd<'_>,
+    cooked: &mut Option<rustix::termios::Termios>,
+    raw: bool,
+) -> io::Result<()> {
+    use rustix::termios::{cfmakeraw, isatty, tcgetattr, tcsetattr, OptionalActions};
+
+    if !isatty(fd) {
+        return Ok(());
+    }
+    if raw {
+        if cooked.is_none() {
+            let saved = tcgetattr(fd)?;
+            let mut raw = saved.clone();
+            cfmakeraw(&mut raw);
+            tcsetattr(fd, OptionalActions::Now, &raw)?;
+            *cooked = Some(saved);
+        }
+    } else if let Some(saved) = cooked.take() {
+        tcsetattr(fd, OptionalActions::Now, &saved)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+impl Stdin {
+    /// Switch the terminal attached to stdin between raw and cooked mode.
+    ///
+    /// In raw mode the guest receives each keystroke as soon as it is typed,
+    /// without line editing or echo, which interactive guests such as shells
+    /// and editors need. The terminal's original mode is restored when raw
+    /// mode is turned off, or when this `Stdin` is dropped.
+    ///
+    /// This is a no-op if stdin is not a terminal.
+    pub fn set_raw_mode(&mut self, raw: bool) -> io::Result<()> {
+        set_terminal_raw_mode(self.0.as_fd(), &mut self.1, raw)
+    }
+
+    /// Whether stdin is in non-blocking mode (`O_NONBLOCK`).
+    pub fn is_nonblocking(&self) -> io::Result<bool> {
+        fd_is_nonblocking(self.0.as_fd())
+    }
+
+    /// Put stdin into or out of non-blocking mode (`O_NONBLOCK`).
+    ///
+    /// In non-blocking mode, a read when no input is available returns zero
+    /// bytes instead of waiting. The flag belongs to the open file
+    /// description, so it is shared with the rest of the host process and
+    /// any other process using the same terminal or pipe.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        fd_set_nonblocking(self.0.as_fd(), nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Stdin {
+    fn drop(&mut self) {
+        if let Err(err) = self.set_raw_mode(false) {
+            tracing::warn!("failed to restore terminal mode: {err}");
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -34,10 +173,19 @@ impl InputStream for Stdin {
     }
 
     async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        #[cfg(windows)]
+        note_stdin_read();
         match Read::read(&mut self.0, buf) {
             Ok(0) => Ok((0, true)),
             Ok(n) => Ok((n as u64, false)),
-            Err(err) if err.kind() == io::ErrorKind::Interrupted => Ok((0, false)),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok((0, false))
+            }
             Err(err) => Err(err.into()),
         }
     }
@@ -45,12 +193,9 @@ impl InputStream for Stdin {
         &mut self,
         bufs: &mut [io::IoSliceMut<'a>],
     ) -> Result<(u64, bool), Error> {
-        match Read::read_vectored(&mut self.0, bufs) {
-            Ok(0) => Ok((0, true)),
-            Ok(n) => Ok((n as u64, false)),
-            Err(err) if err.kind() == io::ErrorKind::Interrupted => Ok((0, false)),
-            Err(err) => Err(err.into()),
-        }
+        #[cfg(windows)]
+        note_stdin_read();
+        read_vectored(&mut self.0, bufs)
     }
     #[cfg(can_vector)]
     fn is_read_vectored(&self) {
@@ -58,16 +203,140 @@ impl InputStream for Stdin {
     }
 
     async fn skip(&mut self, nelem: u64) -> Result<(u64, bool), Error> {
-        let num = io::copy(&mut io::Read::take(&mut self.0, nelem), &mut io::sink())?;
-        Ok((num, num < nelem))
+        #[cfg(windows)]
+        note_stdin_read();
+        Ok(skip_input(&mut self.0, nelem)?)
     }
 
     async fn num_ready_bytes(&self) -> Result<u64, Error> {
         Ok(self.0.num_ready_bytes()?)
     }
 
+    /// Wait until a read won't block, which includes at the end of input.
+    ///
+    /// This blocks the calling thread, like waiting in `poll-oneoff` does.
+    #[cfg(unix)]
     async fn readable(&self) -> Result<(), Error> {
-        Err(anyhow::anyhow!("idk"))
+        use rustix::io::{poll, Errno, PollFd, PollFlags};
+
+        let mut pollfds = [PollFd::from_borrowed_fd(self.0.as_fd(), PollFlags::IN)];
+        loop {
+            match poll(&mut pollfds, -1) {
+                Ok(_) => return Ok(()),
+                Err(Errno::INTR) => continue,
+                Err(err) => return Err(io::Error::from(err).into()),
+            }
+        }
+    }
+
+    /// Wait until a read won't block, which includes at the end of input.
+    ///
+    /// This blocks the calling thread, like waiting in `poll-oneoff` does.
+    #[cfg(windows)]
+    async fn readable(&self) -> Result<(), Error> {
+        match poll_stdin(None) {
+            StdinReadiness::Ready => Ok(()),
+            StdinReadiness::NotReady => unreachable!("waited without a timeout"),
+            StdinReadiness::Error(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Whether the host's stdin has input, as found by [`poll_stdin`].
+#[cfg(windows)]
+pub(crate) enum StdinReadiness {
+    /// A read won't block: there is input, or the input has ended.
+    Ready,
+    /// The timeout passed without any input arriving.
+    NotReady,
+    /// Reading stdin failed.
+    Error(io::Error),
+}
+
+/// The worker thread that waits for input on the host's stdin.
+///
+/// Windows can't poll a console or pipe handle for readability, so, as in
+/// `wasi-common`, a thread waits in `fill_buf` on the process-wide stdin
+/// buffer instead. That reads ahead into the same buffer that reads from
+/// [`Stdin`] are served from, so waiting never loses input.
+#[cfg(windows)]
+struct StdinPoll {
+    /// Sends requests to the thread, tagged with [`STDIN_READS`].
+    request: std::sync::mpsc::Sender<u64>,
+    /// Receives the thread's answers, tagged like the requests.
+    notify: std::sync::mpsc::Receiver<(u64, io::Result<()>)>,
+    /// Whether a request has been sent that has not been answered yet.
+    outstanding: bool,
+}
+
+#[cfg(windows)]
+static STDIN_POLL: std::sync::Mutex<Option<StdinPoll>> = std::sync::Mutex::new(None);
+
+/// The number of reads from [`Stdin`] so far.
+///
+/// An answer to a request made before the latest read may describe input
+/// that has since been consumed, so it is not trusted.
+#[cfg(windows)]
+static STDIN_READS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Note a read from [`Stdin`], see [`STDIN_READS`].
+#[cfg(windows)]
+fn note_stdin_read() {
+    STDIN_READS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Wait up to `timeout`, or forever if it is `None`, for the host's stdin to
+/// have input or reach its end.
+///
+/// A request left unanswered by a timeout stays with the thread, so a later
+/// call with a zero timeout still sees input that arrived in between.
+#[cfg(windows)]
+pub(crate) fn poll_stdin(timeout: Option<std::time::Duration>) -> StdinReadiness {
+    use std::sync::mpsc::{self, RecvTimeoutError};
+
+    let mut stdin_poll = STDIN_POLL.lock().unwrap();
+    let stdin_poll = stdin_poll.get_or_insert_with(|| {
+        let (request, requests) = mpsc::channel::<u64>();
+        let (notify_sender, notify) = mpsc::channel();
+        std::thread::spawn(move || {
+            for reads in requests {
+                let result = io::BufRead::fill_buf(&mut std::io::stdin().lock()).map(|_| ());
+                if notify_sender.send((reads, result)).is_err() {
+                    return;
+                }
+            }
+        });
+        StdinPoll {
+            request,
+            notify,
+            outstanding: false,
+        }
+    });
+
+    let reads = STDIN_READS.load(std::sync::atomic::Ordering::SeqCst);
+    loop {
+        if !stdin_poll.outstanding {
+            stdin_poll
+                .request
+                .send(reads)
+                .expect("stdin poll thread exited");
+            stdin_poll.outstanding = true;
+        }
+        let answer = match timeout {
+            Some(timeout) => match stdin_poll.notify.recv_timeout(timeout) {
+                Ok(answer) => answer,
+                Err(RecvTimeoutError::Timeout) => return StdinReadiness::NotReady,
+                Err(RecvTimeoutError::Disconnected) => panic!("stdin poll thread exited"),
+            },
+            None => stdin_poll.notify.recv().expect("stdin poll thread exited"),
+        };
+        stdin_poll.outstanding = false;
+        match answer {
+            // The guest has read since this request was made; ask again.
+            (asked_at, _) if asked_at != reads => continue,
+            (_, Ok(())) => return StdinReadiness::Ready,
+            (_, Err(err)) => return StdinReadiness::Error(err),
+        }
     }
 }
 #[cfg(windows)]
@@ -90,8 +359,157 @@ impl AsFd for Stdin {
     }
 }
 
+/// Write as much of `bufs` as possible, continuing after short writes and
+/// stopping early only if `w` is non-blocking and can't accept more.
+///
+/// `Write::write_vectored` may write only part of the data, e.g. only the first
+/// slice, but guests expect a stream write to deliver everything it reports,
+/// so the remainder is written out here rather than silently dropped.
+fn write_all_vectored(w: &mut impl Write, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+    let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+    if total == 0 {
+        return Ok(0);
+    }
+    let mut skip = loop {
+        match Write::write_vectored(w, bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => break n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+            Err(err) => return Err(err),
+        }
+    };
+    let mut written = skip;
+    for buf in bufs {
+        if skip >= buf.len() {
+            skip -= buf.len();
+            continue;
+        }
+        let rest = &buf[skip..];
+        let n = write_whole(w, rest)?;
+        written += n;
+        if n < rest.len() {
+            break;
+        }
+        skip = 0;
+    }
+    Ok(written)
+}
+
+/// Write as much of `buf` as possible with repeated calls to `w.write`,
+/// stopping early only if `w` is non-blocking and can't accept more.
+///
+/// Called with a stdio lock held, this keeps a guest write from being split
+/// by host output written between partial writes.
+fn write_whole(w: &mut impl Write, buf: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        match Write::write(w, &buf[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(written)
+}
+
+/// Flush `w` after a write. If `w` is non-blocking and can't accept the
+/// buffered output yet, it stays buffered and goes out with a later write or
+/// flush.
+fn flush_after_write(w: &mut impl Write) -> io::Result<()> {
+    match Write::flush(w) {
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
+
+/// Flush `w`, waiting for its file descriptor `fd` to accept more output
+/// whenever it is non-blocking and can't yet.
+///
+/// This blocks the calling thread, like waiting in `poll-oneoff` does.
+#[cfg(unix)]
+fn flush_all(w: &mut impl Write, fd: BorrowedFd<'_>) -> io::Result<()> {
+    use rustix::io::{poll, Errno, PollFd, PollFlags};
+
+    loop {
+        match Write::flush(w) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let mut pollfds = [PollFd::from_borrowed_fd(fd, PollFlags::OUT)];
+                match poll(&mut pollfds, -1) {
+                    Ok(_) | Err(Errno::INTR) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// The most zeroes [`write_zeroes_chunked`] writes between yields.
+const ZEROES_CHUNK: usize = 64 * 1024;
+
+static ZEROES: [u8; ZEROES_CHUNK] = [0; ZEROES_CHUNK];
+
+/// Write `nelem` zeroes with `write`, which is called with a chunk of zeroes
+/// at a time and returns how many it wrote.
+///
+/// Yields to the executor between chunks so that zeroing a large region
+/// doesn't stall other tasks, and stops early if `write` comes up short.
+async fn write_zeroes_chunked(
+    mut write: impl FnMut(&[u8]) -> io::Result<usize> + Send,
+    nelem: u64,
+) -> io::Result<u64> {
+    let mut written = 0;
+    while written < nelem {
+        let len = (nelem - written).min(ZEROES_CHUNK as u64) as usize;
+        let n = write(&ZEROES[..len])?;
+        written += n as u64;
+        if n < len {
+            break;
+        }
+        yield_now().await;
+    }
+    Ok(written)
+}
+
 macro_rules! wasi_output_stream_impl {
     ($ty:ty, $ident:ident) => {
+        impl $ty {
+            /// Flush the host stream after every write instead of leaving
+            /// output in the host's buffer until a newline or until it fills.
+            ///
+            /// This minimizes latency for interactive or streaming uses, such
+            /// as forwarding guest output to a terminal or over the network as
+            /// it is produced, at the cost of a flush per guest write, which
+            /// can substantially reduce throughput for guests that issue many
+            /// small writes.
+            pub fn unbuffered(mut self) -> Self {
+                self.1 = true;
+                self
+            }
+
+            /// Whether this stream is in non-blocking mode (`O_NONBLOCK`).
+            #[cfg(unix)]
+            pub fn is_nonblocking(&self) -> io::Result<bool> {
+                fd_is_nonblocking(self.0.as_fd())
+            }
+
+            /// Put this stream into or out of non-blocking mode
+            /// (`O_NONBLOCK`).
+            ///
+            /// In non-blocking mode, a write that cannot proceed immediately
+            /// accepts zero bytes instead of waiting. The flag belongs to the
+            /// open file description, so it is shared with the rest of the
+            /// host process.
+            #[cfg(unix)]
+            pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+                fd_set_nonblocking(self.0.as_fd(), nonblocking)
+            }
+        }
+
         #[async_trait::async_trait]
         impl OutputStream for $ty {
             fn as_any(&self) -> &dyn Any {
@@ -108,11 +526,19 @@ macro_rules! wasi_output_stream_impl {
             }
 
             async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
-                let n = Write::write(&mut self.0, buf)?;
+                let mut lock = self.0.lock();
+                let n = write_whole(&mut lock, buf)?;
+                if self.1 {
+                    flush_after_write(&mut lock)?;
+                }
                 Ok(n.try_into()?)
             }
             async fn write_vectored<'a>(&mut self, bufs: &[io::IoSlice<'a>]) -> Result<u64, Error> {
-                let n = Write::write_vectored(&mut self.0, bufs)?;
+                let mut lock = self.0.lock();
+                let n = write_all_vectored(&mut lock, bufs)?;
+                if self.1 {
+                    flush_after_write(&mut lock)?;
+                }
                 Ok(n.try_into()?)
             }
             #[cfg(can_vector)]
@@ -131,10 +557,31 @@ macro_rules! wasi_output_stream_impl {
             */
 
             async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
-                let num = io::copy(&mut io::Read::take(io::repeat(0), nelem), &mut self.0)?;
+                let stream = &self.0;
+                let num =
+                    write_zeroes_chunked(|zeroes| write_whole(&mut stream.lock(), zeroes), nelem)
+                        .await?;
+                if self.1 {
+                    flush_after_write(&mut self.0.lock())?;
+                }
                 Ok(num)
             }
 
+            async fn shutdown(&mut self) -> Result<(), Error> {
+                self.sync_data().await
+            }
+
+            #[cfg(unix)]
+            async fn sync_data(&mut self) -> Result<(), Error> {
+                flush_all(&mut self.0.lock(), self.0.as_fd())?;
+                Ok(())
+            }
+            #[cfg(windows)]
+            async fn sync_data(&mut self) -> Result<(), Error> {
+                Write::flush(&mut self.0.lock())?;
+                Ok(())
+            }
+
             async fn writable(&self) -> Result<(), Error> {
                 Ok(())
             }
@@ -161,16 +608,231 @@ macro_rules! wasi_output_stream_impl {
     };
 }
 
-pub struct Stdout(std::io::Stdout);
+/// The host process's standard output, as a guest output stream.
+///
+/// Each guest write holds the same lock as the host's `print!` and
+/// `println!` until all of its bytes are written, so host output never
+/// lands in the middle of a guest write, and a guest write never lands in
+/// the middle of a host `println!`. The exception is `write-zeroes`, which
+/// yields between chunks of zeroes and can't hold the lock across a yield,
+/// so it takes the lock again for each chunk.
+pub struct Stdout(std::io::Stdout, bool);
 
 pub fn stdout() -> Stdout {
-    Stdout(std::io::stdout())
+    Stdout(std::io::stdout(), false)
 }
 wasi_output_stream_impl!(Stdout, Stdout);
 
-pub struct Stderr(std::io::Stderr);
+/// The host process's standard error, as a guest output stream.
+///
+/// Each guest write holds the same lock as the host's `eprint!` and
+/// `eprintln!` until all of its bytes are written, so a guest line written
+/// in one write and a host `eprintln!` stay whole even when they race. As
+/// with [`Stdout`], `write-zeroes` takes the lock again for each chunk.
+pub struct Stderr(std::io::Stderr, bool);
 
 pub fn stderr() -> Stderr {
-    Stderr(std::io::stderr())
+    Stderr(std::io::stderr(), false)
 }
 wasi_output_stream_impl!(Stderr, Stderr);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A writer that accepts at most three bytes per call.
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_vectored_write_is_completed() {
+        let mut w = Trickle(Vec::new());
+        let bufs = [
+            io::IoSlice::new(b"hello"),
+            io::IoSlice::new(b""),
+            io::IoSlice::new(b", world"),
+        ];
+        assert_eq!(write_all_vectored(&mut w, &bufs).unwrap(), 12);
+        assert_eq!(w.0, b"hello, world");
+    }
+
+    #[test]
+    fn vectored_read_reports_end_after_data() {
+        let mut input = io::Cursor::new(b"abc".to_vec());
+        let (mut first, mut second) = ([0; 2], [0; 4]);
+        let mut bufs = [
+            io::IoSliceMut::new(&mut first),
+            io::IoSliceMut::new(&mut second),
+        ];
+        // Fewer bytes than the buffers can hold are delivered, without the end.
+        assert_eq!(read_vectored(&mut input, &mut bufs).unwrap(), (3, false));
+        assert_eq!(&first, b"ab");
+        assert_eq!(second[0], b'c');
+        let mut bufs = [
+            io::IoSliceMut::new(&mut first),
+            io::IoSliceMut::new(&mut second),
+        ];
+        assert_eq!(read_vectored(&mut input, &mut bufs).unwrap(), (0, true));
+    }
+
+    #[test]
+    fn vectored_read_into_nothing_is_not_end() {
+        let mut input = io::Cursor::new(b"abc".to_vec());
+        let mut bufs = [io::IoSliceMut::new(&mut [])];
+        assert_eq!(read_vectored(&mut input, &mut bufs).unwrap(), (0, false));
+    }
+
+    #[test]
+    fn partial_write_is_completed() {
+        let mut w = Trickle(Vec::new());
+        assert_eq!(write_whole(&mut w, b"a whole line\n").unwrap(), 13);
+        assert_eq!(w.0, b"a whole line\n");
+    }
+
+    #[tokio::test]
+    async fn write_zeroes_in_chunks() {
+        let mut w = Vec::new();
+        let mut calls = 0;
+        let nelem = 5 * 1024 * 1024 + 7;
+        let n = write_zeroes_chunked(
+            |zeroes| {
+                calls += 1;
+                w.write(zeroes)
+            },
+            nelem,
+        )
+        .await
+        .unwrap();
+        assert_eq!(n, nelem);
+        assert_eq!(w.len() as u64, nelem);
+        assert!(w.iter().all(|&b| b == 0));
+        assert_eq!(calls, 81);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_mode_is_entered_and_left() {
+        use rustix::fs::{open, Mode, OFlags};
+        use rustix::pty::{grantpt, openpt, ptsname, unlockpt, OpenptFlags};
+        use rustix::termios::{tcgetattr, ECHO, ICANON};
+
+        let controller = openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY).unwrap();
+        grantpt(&controller).unwrap();
+        unlockpt(&controller).unwrap();
+        let name = ptsname(&controller, Vec::new()).unwrap();
+        let tty = open(
+            name.as_c_str(),
+            OFlags::RDWR | OFlags::NOCTTY,
+            Mode::empty(),
+        )
+        .unwrap();
+        let before = tcgetattr(&tty).unwrap();
+        assert_ne!(before.c_lflag & (ECHO | ICANON), 0);
+
+        let mut cooked = None;
+        set_terminal_raw_mode(tty.as_fd(), &mut cooked, true).unwrap();
+        assert_eq!(tcgetattr(&tty).unwrap().c_lflag & (ECHO | ICANON), 0);
+        // Entering raw mode again keeps the attributes saved the first time.
+        set_terminal_raw_mode(tty.as_fd(), &mut cooked, true).unwrap();
+        assert_eq!(cooked.as_ref().unwrap().c_lflag, before.c_lflag);
+
+        set_terminal_raw_mode(tty.as_fd(), &mut cooked, false).unwrap();
+        assert!(cooked.is_none());
+        assert_eq!(tcgetattr(&tty).unwrap().c_lflag, before.c_lflag);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_mode_ignores_non_terminals() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let mut cooked = None;
+        set_terminal_raw_mode(file.as_fd(), &mut cooked, true).unwrap();
+        assert!(cooked.is_none());
+    }
+
+    /// A pipe whose ends are non-blocking, with its write end already full.
+    #[cfg(unix)]
+    fn full_nonblocking_pipe() -> (std::fs::File, std::fs::File) {
+        let (reader, writer) = rustix::io::pipe().unwrap();
+        let (reader, mut writer) = (std::fs::File::from(reader), std::fs::File::from(writer));
+        fd_set_nonblocking(reader.as_fd(), true).unwrap();
+        fd_set_nonblocking(writer.as_fd(), true).unwrap();
+        let chunk = [0; 64 * 1024];
+        while write_whole(&mut writer, &chunk).unwrap() == chunk.len() {}
+        (reader, writer)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblocking_writes_stop_when_full() {
+        let (mut reader, mut writer) = full_nonblocking_pipe();
+        assert_eq!(write_whole(&mut writer, b"more").unwrap(), 0);
+        let bufs = [io::IoSlice::new(b"more"), io::IoSlice::new(b"still")];
+        assert_eq!(write_all_vectored(&mut writer, &bufs).unwrap(), 0);
+
+        // Once there is room again, writes go through.
+        let mut buf = [0; 64 * 1024];
+        while skip_input(&mut reader, buf.len() as u64).unwrap().0 > 0 {}
+        assert_eq!(write_all_vectored(&mut writer, &bufs).unwrap(), 9);
+        assert_eq!(io::Read::read(&mut reader, &mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"morestill");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblocking_flush_waits_for_room() {
+        let (mut reader, writer) = full_nonblocking_pipe();
+        let mut buffered = io::BufWriter::new(&writer);
+        buffered.write_all(b"pending").unwrap();
+        assert_eq!(
+            Write::flush(&mut buffered).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        flush_after_write(&mut buffered).unwrap();
+
+        let drain = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0; 64 * 1024];
+            while !received.ends_with(b"pending") {
+                match io::Read::read(&mut reader, &mut buf) {
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => std::thread::yield_now(),
+                    Err(err) => panic!("{err}"),
+                }
+            }
+        });
+        flush_all(&mut buffered, writer.as_fd()).unwrap();
+        drain.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblocking_skip_stops_when_empty() {
+        let (mut reader, writer) = full_nonblocking_pipe();
+        drop(writer);
+        let mut total = 0;
+        loop {
+            let (n, end) = skip_input(&mut reader, u64::MAX).unwrap();
+            total += n;
+            if end {
+                break;
+            }
+        }
+        assert!(total > 0);
+
+        let (reader, _writer) = rustix::io::pipe().unwrap();
+        let mut reader = std::fs::File::from(reader);
+        fd_set_nonblocking(reader.as_fd(), true).unwrap();
+        assert_eq!(skip_input(&mut reader, 10).unwrap(), (0, false));
+    }
+}
@@ -1,6 +1,70 @@
 use crate::preview2::{Table, TableError};
 use anyhow::Error;
 use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// How many iterations the byte-at-a-time loops below run between yields.
+const YIELD_INTERVAL: u64 = 64 * 1024;
+
+/// Yield to the executor once.
+///
+/// Long-running host operations call this periodically so that the embedder
+/// can interrupt them, e.g. by dropping the future when an epoch or timeout
+/// deadline passes, instead of having to wait for them to finish.
+pub(crate) async fn yield_now() {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    YieldNow(false).await
+}
+
+/// Implement `pollable_read` for a wrapper stream by forwarding it to the
+/// inner stream in the field `$inner`, e.g. `forward_input_stream!(inner);`.
+macro_rules! forward_input_stream {
+    ($inner:tt) => {
+        #[cfg(unix)]
+        fn pollable_read(&self) -> Option<rustix::fd::BorrowedFd> {
+            self.$inner.pollable_read()
+        }
+
+        #[cfg(windows)]
+        fn pollable_read(&self) -> Option<io_extras::os::windows::BorrowedHandleOrSocket> {
+            self.$inner.pollable_read()
+        }
+    };
+}
+pub(crate) use forward_input_stream;
+
+/// Implement `pollable_write` for a wrapper stream by forwarding it to the
+/// inner stream in the field `$inner`, like [`forward_input_stream`].
+macro_rules! forward_output_stream {
+    ($inner:tt) => {
+        #[cfg(unix)]
+        fn pollable_write(&self) -> Option<rustix::fd::BorrowedFd> {
+            self.$inner.pollable_write()
+        }
+
+        #[cfg(windows)]
+        fn pollable_write(&self) -> Option<io_extras::os::windows::BorrowedHandleOrSocket> {
+            self.$inner.pollable_write()
+        }
+    };
+}
+pub(crate) use forward_output_stream;
 
 /// An input bytestream.
 ///
@@ -27,6 +91,11 @@ pub trait InputStream: Send + Sync {
 
     /// Read bytes. On success, returns a pair holding the number of bytes read
     /// and a flag indicating whether the end of the stream was reached.
+    ///
+    /// The returned future may be dropped before it completes, so it must not
+    /// take data from its source across an `.await`: anything taken has to be
+    /// stored in the stream before the next suspension point, where a later
+    /// read will find it.
     async fn read(&mut self, _buf: &mut [u8]) -> Result<(u64, bool), Error> {
         Err(anyhow::anyhow!("badf"))
     }
@@ -51,7 +120,10 @@ pub trait InputStream: Send + Sync {
         let mut saw_end = false;
 
         // TODO: Optimize by reading more than one byte at a time.
-        for _ in 0..nelem {
+        for i in 0..nelem {
+            if i != 0 && i % YIELD_INTERVAL == 0 {
+                yield_now().await;
+            }
             let (num, end) = self.read(&mut [0]).await?;
             nread += num;
             if end {
@@ -121,7 +193,10 @@ pub trait OutputStream: Send + Sync {
         let mut saw_end = false;
 
         // TODO: Optimize by splicing more than one byte at a time.
-        for _ in 0..nelem {
+        for i in 0..nelem {
+            if i != 0 && i % YIELD_INTERVAL == 0 {
+                yield_now().await;
+            }
             let mut buf = [0u8];
             let (num, end) = src.read(&mut buf).await?;
             self.write(&buf).await?;
@@ -140,7 +215,10 @@ pub trait OutputStream: Send + Sync {
         let mut nwritten = 0;
 
         // TODO: Optimize by writing more than one byte at a time.
-        for _ in 0..nelem {
+        for i in 0..nelem {
+            if i != 0 && i % YIELD_INTERVAL == 0 {
+                yield_now().await;
+            }
             let num = self.write(&[0]).await?;
             if num == 0 {
                 break;
@@ -151,6 +229,31 @@ pub trait OutputStream: Send + Sync {
         Ok(nwritten)
     }
 
+    /// Signal that nothing more will be written to this stream, e.g. so that
+    /// the reader on the other end sees the end of the stream, or so that
+    /// buffered output is flushed.
+    ///
+    /// This is called when the guest drops the stream. The default does
+    /// nothing.
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Flush buffered bytes and, if this stream writes to a file, wait for
+    /// the file's data to reach storage, like `fdatasync` in POSIX.
+    ///
+    /// Streams with nowhere durable to write, such as in-memory pipes, treat
+    /// this as a no-op. The default does nothing.
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Like [`sync_data`](Self::sync_data), but also wait for the file's
+    /// metadata to reach storage, like `fsync` in POSIX.
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.sync_data().await
+    }
+
     /// Test whether this stream is writable.
     async fn writable(&self) -> Result<(), Error>;
 }
@@ -207,4 +310,88 @@ mod test {
         let _ = table.get_output_stream(ix).unwrap();
         let _ = table.get_output_stream_mut(ix).unwrap();
     }
+
+    /// An output stream that only counts what is written to it, relying on the
+    /// trait's default `write_zeroes`.
+    struct CountingSink(u64);
+
+    #[async_trait::async_trait]
+    impl OutputStream for CountingSink {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+        async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len() as u64)
+        }
+        async fn writable(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn huge_write_zeroes_can_be_cancelled() {
+        let mut sink = CountingSink(0);
+        tokio::select! {
+            biased;
+            _ = sink.write_zeroes(u64::MAX) => panic!("write_zeroes should not finish"),
+            _ = async {} => {}
+        }
+        assert!(sink.0 > 0);
+        assert!(sink.0 <= YIELD_INTERVAL);
+    }
+}
+
+/// Helpers shared by the stream tests of several modules.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::convert::TryInto;
+    use std::sync::{Arc, Mutex};
+
+    /// A source whose chunks are pushed by the test, and which has nothing ready
+    /// until one is.
+    pub(crate) struct Gated(pub(crate) Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+    #[async_trait::async_trait]
+    impl InputStream for Gated {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+            let mut chunk = std::future::poll_fn(|_| match self.0.lock().unwrap().pop_front() {
+                Some(chunk) => Poll::Ready(chunk),
+                None => Poll::Pending,
+            })
+            .await;
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.0.lock().unwrap().push_front(chunk.split_off(n));
+            }
+            Ok((n.try_into()?, false))
+        }
+
+        async fn readable(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    impl futures_core::Stream for Gated {
+        type Item = Result<Vec<u8>, Error>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.0.lock().unwrap().pop_front() {
+                Some(chunk) => Poll::Ready(Some(Ok(chunk))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    pub(crate) async fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx))).await
+    }
 }
@@ -87,6 +87,17 @@ impl Table {
         }
     }
 
+    /// Iterate over the resources of a given type, in order of their indices.
+    pub fn iter_of<T: Any + Sized>(&self) -> impl Iterator<Item = (u32, &T)> {
+        let mut entries: Vec<(u32, &T)> = self
+            .map
+            .iter()
+            .filter_map(|(key, r)| r.downcast_ref::<T>().map(|r| (*key, r)))
+            .collect();
+        entries.sort_by_key(|(key, _)| *key);
+        entries.into_iter()
+    }
+
     /// Remove a resource at a given index from the table.
     pub fn delete<T: Any + Sized>(&mut self, key: u32) -> Result<T, TableError> {
         // Optimistically attempt to remove the value stored under key
@@ -0,0 +1,200 @@
+//! Mirroring streams to a secondary sink.
+//!
+//! The tees in this module pass data between the guest and an inner stream
+//! unchanged, and copy it to a [`Mirror`] as it goes by. The mirror writes on
+//! a background thread, so a slow mirror destination such as a log file on
+//! disk never blocks the guest; if the mirror falls behind, copies are dropped
+//! and counted instead.
+
+use crate::preview2::stream::{
+    forward_input_stream, forward_output_stream, InputStream, OutputStream,
+};
+use anyhow::Error;
+use std::any::Any;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+
+/// A background writer receiving copies of stream data.
+///
+/// Clones share the same background thread and dropped-bytes counter. The
+/// thread exits, flushing its writer, once every clone has been dropped and
+/// all queued copies are written.
+pub struct Mirror {
+    // `SyncSender` is not `Sync` on all supported Rust versions, so guard it
+    // with a mutex.
+    sender: Mutex<SyncSender<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Mirror {
+    /// Start a thread writing mirrored data to `writer`, with room for `bound`
+    /// pending copies before further copies are dropped.
+    pub fn new(mut writer: impl Write + Send + 'static, bound: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(bound);
+        std::thread::spawn(move || {
+            for chunk in receiver {
+                if let Err(err) = writer.write_all(&chunk) {
+                    tracing::warn!("stream mirror write failed: {err}");
+                    return;
+                }
+            }
+            if let Err(err) = writer.flush() {
+                tracing::warn!("stream mirror flush failed: {err}");
+            }
+        });
+        Self {
+            sender: Mutex::new(sender),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The number of bytes that were not mirrored because the background
+    /// writer could not keep up or had failed.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let sender = self.sender.lock().unwrap();
+        if sender.try_send(bytes.to_vec()).is_err() {
+            self.dropped
+                .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Clone for Mirror {
+    fn clone(&self) -> Self {
+        Self {
+            sender: Mutex::new(self.sender.lock().unwrap().clone()),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+/// An input stream that mirrors everything the guest reads from an inner
+/// stream.
+pub struct TeeInputStream<T> {
+    inner: T,
+    mirror: Mirror,
+}
+
+impl<T: InputStream> TeeInputStream<T> {
+    /// Create a stream reading from `inner` and copying the bytes the guest
+    /// reads to `mirror`.
+    pub fn new(inner: T, mirror: Mirror) -> Self {
+        Self { inner, mirror }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for TeeInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let (n, end) = self.inner.read(buf).await?;
+        self.mirror.send(&buf[..usize::try_from(n)?]);
+        Ok((n, end))
+    }
+}
+
+/// An output stream that mirrors everything the guest writes to an inner
+/// stream.
+pub struct TeeOutputStream<T> {
+    inner: T,
+    mirror: Mirror,
+}
+
+impl<T: OutputStream> TeeOutputStream<T> {
+    /// Create a stream writing to `inner` and copying the bytes it accepts
+    /// to `mirror`.
+    pub fn new(inner: T, mirror: Mirror) -> Self {
+        Self { inner, mirror }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for TeeOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        self.mirror.send(&buf[..usize::try_from(n)?]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::pipe::{ReadPipe, WritePipe};
+
+    /// A writer that hands everything it receives to a channel.
+    struct Forward(mpsc::Sender<Vec<u8>>);
+
+    impl Write for Forward {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.send(buf.to_vec()).unwrap();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn tees_mirror_data() {
+        let (sender, receiver) = mpsc::channel();
+        let mirror = Mirror::new(Forward(sender), 16);
+
+        let mut input = TeeInputStream::new(ReadPipe::from("in"), mirror.clone());
+        let mut buf = [0; 16];
+        input.read(&mut buf).await.unwrap();
+
+        let capture = WritePipe::new_in_memory();
+        let mut output = TeeOutputStream::new(capture.clone(), mirror.clone());
+        output.write(b"out").await.unwrap();
+        assert_eq!(capture.take_contents(), b"out");
+
+        drop((input, output, mirror));
+        let mirrored: Vec<u8> = receiver.iter().flatten().collect();
+        assert_eq!(mirrored, b"inout");
+    }
+}
@@ -0,0 +1,556 @@
+//! Streams for testing stream consumers.
+//!
+//! The wrappers and sources in this module reproduce awkward but legal stream
+//! behavior, such as data split at arbitrary points, short reads and delays
+//! between chunks, so that code consuming WASI streams can be tested against
+//! it. [`ExpectingOutputStream`] checks what a guest writes instead. The
+//! random choices are made from a seed, so a failing run can be reproduced.
+
+use crate::preview2::clocks::WasiMonotonicClock;
+use crate::preview2::stream::{forward_input_stream, InputStream, OutputStream};
+use anyhow::Error;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An input stream that delivers predefined chunks at predefined times.
+///
+/// Each chunk becomes readable once the given monotonic clock reaches its
+/// timestamp. Until then, reads return zero bytes without reaching the end of
+/// the stream; the end is reached once every chunk has been read. Paired with a
+/// manually advanced clock, this gives deterministic tests of guests that
+/// consume input over time.
+pub struct ScheduledInputStream {
+    clock: Box<dyn WasiMonotonicClock>,
+    /// The remaining chunks, in delivery order, with their delivery times.
+    chunks: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl ScheduledInputStream {
+    /// Create a stream delivering each `(instant, bytes)` chunk once `clock`
+    /// reaches `instant`, in nanoseconds. Chunks are delivered in order of
+    /// their instants.
+    pub fn new(clock: impl WasiMonotonicClock + 'static, mut chunks: Vec<(u64, Vec<u8>)>) -> Self {
+        chunks.sort_by_key(|(when, _)| *when);
+        Self {
+            clock: Box::new(clock),
+            chunks: chunks.into(),
+        }
+    }
+
+    /// The chunks whose delivery time has been reached.
+    fn due(&self) -> impl Iterator<Item = &Vec<u8>> {
+        let now = self.clock.now();
+        self.chunks
+            .iter()
+            .take_while(move |(when, _)| *when <= now)
+            .map(|(_, chunk)| chunk)
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for ScheduledInputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let now = self.clock.now();
+        let mut nread = 0;
+        while nread < buf.len() {
+            match self.chunks.front_mut() {
+                Some((when, chunk)) if *when <= now => {
+                    let n = chunk.len().min(buf.len() - nread);
+                    buf[nread..][..n].copy_from_slice(&chunk[..n]);
+                    chunk.drain(..n);
+                    nread += n;
+                    if chunk.is_empty() {
+                        self.chunks.pop_front();
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok((nread.try_into()?, self.chunks.is_empty()))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.due().map(Vec::len).sum::<usize>().try_into()?)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An input stream that delivers predefined chunks, one per read.
+///
+/// A read never combines bytes from two chunks, and a chunk larger than the
+/// guest's buffer is delivered over several reads, so tests control exactly
+/// where short reads happen. An empty chunk makes a read return zero bytes
+/// without reaching the end of the stream. The end is reached once every
+/// chunk has been read.
+pub struct ChunkedInputStream {
+    chunks: VecDeque<Vec<u8>>,
+}
+
+impl ChunkedInputStream {
+    /// Create a stream delivering `chunks` in order.
+    pub fn new(chunks: impl Into<VecDeque<Vec<u8>>>) -> Self {
+        Self {
+            chunks: chunks.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl InputStream for ChunkedInputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let n = match self.chunks.front_mut() {
+            Some(chunk) => {
+                let n = chunk.len().min(buf.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                chunk.drain(..n);
+                if chunk.is_empty() {
+                    self.chunks.pop_front();
+                }
+                n
+            }
+            None => 0,
+        };
+        Ok((n.try_into()?, self.chunks.is_empty()))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.chunks.front().map_or(0, Vec::len).try_into()?)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An input stream that randomly shortens reads from an inner stream.
+///
+/// About half of all reads into a buffer longer than one byte are cut to a
+/// random length, sometimes a single byte, even when more data is available.
+/// This mimics how network streams behave and helps flush out guests that
+/// assume a read fills its whole buffer. The randomness is seeded, so a
+/// failing run can be reproduced.
+pub struct ChaosInputStream<T> {
+    inner: T,
+    rng: cap_rand::rngs::StdRng,
+}
+
+impl<T: InputStream> ChaosInputStream<T> {
+    /// Create a stream reading `inner`, with `seed` determining which reads
+    /// are shortened and to what length.
+    pub fn new(inner: T, seed: u64) -> Self {
+        use cap_rand::SeedableRng;
+        Self {
+            inner,
+            rng: cap_rand::rngs::StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for ChaosInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        use cap_rand::Rng;
+        let len = if buf.len() > 1 && self.rng.gen_bool(0.5) {
+            self.rng.gen_range(1..=buf.len())
+        } else {
+            buf.len()
+        };
+        self.inner.read(&mut buf[..len]).await
+    }
+}
+
+/// An input stream that delays data from an inner stream by random amounts,
+/// like a network with jitter.
+///
+/// Each chunk read from the inner stream becomes readable after a random
+/// delay of up to `max_delay` on the given clock. Delivery keeps the order of
+/// the bytes, so a chunk delayed less than the one before it waits for that
+/// one, which makes bursts arrive together the way they do over TCP. With
+/// [`split_chunks`](Self::split_chunks), chunks are also cut into pieces that
+/// are delayed separately. Until data is due, reads return zero bytes without
+/// reaching the end of the stream. The randomness is seeded, so a failing run
+/// can be reproduced.
+pub struct JitterInputStream<T> {
+    inner: T,
+    clock: Box<dyn WasiMonotonicClock>,
+    rng: cap_rand::rngs::StdRng,
+    max_delay: u64,
+    split_chunks: bool,
+    max_buffer: usize,
+    /// Data read from the inner stream, with the time each piece is due.
+    pending: VecDeque<(u64, Vec<u8>)>,
+    inner_ended: bool,
+}
+
+impl<T: InputStream> JitterInputStream<T> {
+    /// Create a stream delaying each chunk of `inner` by up to `max_delay`,
+    /// measured on `clock`, with `seed` determining the delays.
+    pub fn new(
+        inner: T,
+        clock: impl WasiMonotonicClock + 'static,
+        max_delay: Duration,
+        seed: u64,
+    ) -> Self {
+        use cap_rand::SeedableRng;
+        Self {
+            inner,
+            clock: Box::new(clock),
+            rng: cap_rand::rngs::StdRng::seed_from_u64(seed),
+            max_delay: max_delay.as_nanos().try_into().unwrap_or(u64::MAX),
+            split_chunks: false,
+            max_buffer: usize::MAX,
+            pending: VecDeque::new(),
+            inner_ended: false,
+        }
+    }
+
+    /// Cut each chunk read from the inner stream into randomly sized pieces,
+    /// each with its own delay.
+    pub fn split_chunks(mut self) -> Self {
+        self.split_chunks = true;
+        self
+    }
+
+    /// Hold at most `limit` bytes that are waiting to be delivered. Once
+    /// that many are held, reads stop taking data from the inner stream
+    /// until some of it has been delivered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn with_max_buffer(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "max buffer must be nonzero");
+        self.max_buffer = limit;
+        self
+    }
+
+    /// Schedule the delivery of `chunk`, read from the inner stream at `now`.
+    fn schedule(&mut self, now: u64, mut chunk: Vec<u8>) {
+        use cap_rand::Rng;
+        while !chunk.is_empty() {
+            let len = if self.split_chunks {
+                self.rng.gen_range(1..=chunk.len())
+            } else {
+                chunk.len()
+            };
+            let rest = chunk.split_off(len);
+            let due = now.saturating_add(self.rng.gen_range(0..=self.max_delay));
+            let after = self.pending.back().map_or(0, |(when, _)| *when);
+            self.pending.push_back((due.max(after), chunk));
+            chunk = rest;
+        }
+    }
+
+    /// The pieces whose delivery time has been reached.
+    fn due(&self) -> impl Iterator<Item = &Vec<u8>> {
+        let now = self.clock.now();
+        self.pending
+            .iter()
+            .take_while(move |(when, _)| *when <= now)
+            .map(|(_, piece)| piece)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for JitterInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        let held: usize = self.pending.iter().map(|(_, piece)| piece.len()).sum();
+        let room = buf.len().min(self.max_buffer.saturating_sub(held));
+        if !self.inner_ended && room != 0 {
+            let mut chunk = vec![0; room];
+            let (n, end) = self.inner.read(&mut chunk).await?;
+            chunk.truncate(n.try_into()?);
+            self.inner_ended = end;
+            let now = self.clock.now();
+            self.schedule(now, chunk);
+        }
+
+        let now = self.clock.now();
+        let mut nread = 0;
+        while nread < buf.len() {
+            match self.pending.front_mut() {
+                Some((when, piece)) if *when <= now => {
+                    let n = piece.len().min(buf.len() - nread);
+                    buf[nread..][..n].copy_from_slice(&piece[..n]);
+                    piece.drain(..n);
+                    nread += n;
+                    if piece.is_empty() {
+                        self.pending.pop_front();
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok((
+            nread.try_into()?,
+            self.inner_ended && self.pending.is_empty(),
+        ))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(self.due().map(Vec::len).sum::<usize>().try_into()?)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An output stream that checks the guest writes exactly an expected sequence
+/// of bytes.
+///
+/// Each write is compared against the next expected bytes as it arrives, and
+/// fails at the first byte that differs or goes past the end of the
+/// expectation. Clones share the same position, so a clone can be kept to
+/// [`finish`](Self::finish) the check after another is handed to a
+/// `WasiCtx`. The check is also finished by
+/// [`shutdown`](OutputStream::shutdown), when the guest drops the stream.
+#[derive(Debug, Clone)]
+pub struct ExpectingOutputStream {
+    expected: Arc<[u8]>,
+    /// How many of the expected bytes have been written so far.
+    position: Arc<Mutex<usize>>,
+}
+
+impl ExpectingOutputStream {
+    /// Create a stream expecting exactly the bytes of `expected`.
+    pub fn new(expected: impl Into<Vec<u8>>) -> Self {
+        Self {
+            expected: expected.into().into(),
+            position: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Check that all of the expected bytes have been written.
+    pub fn finish(&self) -> Result<(), Error> {
+        let position = *self.position.lock().unwrap();
+        if position < self.expected.len() {
+            return Err(anyhow::anyhow!(
+                "output ended at offset {position}, expected {} bytes",
+                self.expected.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputStream for ExpectingOutputStream {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let expected = &self.expected[*position..];
+        if let Some(i) = buf.iter().zip(expected).position(|(a, b)| a != b) {
+            return Err(anyhow::anyhow!(
+                "output differs from expected at offset {}",
+                *position + i
+            ));
+        }
+        if buf.len() > expected.len() {
+            return Err(anyhow::anyhow!(
+                "output continues past the expected {} bytes",
+                self.expected.len()
+            ));
+        }
+        *position += buf.len();
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.finish()
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+    use crate::preview2::pipe::ReadPipe;
+    use crate::preview2::stream::test_util::{poll_once, Gated};
+
+    #[tokio::test]
+    async fn scheduled_input_stream() {
+        let clock = TickClock::new(0);
+        let mut stream = ScheduledInputStream::new(
+            clock.clone(),
+            vec![(200, b"later".to_vec()), (100, b"first".to_vec())],
+        );
+        let mut buf = [0; 16];
+
+        assert_eq!(stream.num_ready_bytes().await.unwrap(), 0);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+
+        clock.set(100);
+        assert_eq!(stream.num_ready_bytes().await.unwrap(), 5);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (5, false));
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+
+        clock.set(250);
+        assert_eq!(stream.read(&mut buf[..3]).await.unwrap(), (3, false));
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (2, true));
+        assert_eq!(&buf[..2], b"er");
+    }
+
+    #[tokio::test]
+    async fn chaos_input_stream_delivers_everything() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut stream = ChaosInputStream::new(ReadPipe::from(data.clone()), 42);
+        let mut contents = Vec::new();
+        let mut short_reads = 0;
+        let mut buf = [0; 32];
+        loop {
+            let (n, end) = stream.read(&mut buf).await.unwrap();
+            if end {
+                break;
+            }
+            if n < 32 {
+                short_reads += 1;
+            }
+            contents.extend_from_slice(&buf[..n as usize]);
+        }
+        assert_eq!(contents, data);
+        assert!(short_reads > 1);
+    }
+
+    #[tokio::test]
+    async fn expecting_output_stream() {
+        let stream = ExpectingOutputStream::new("hello");
+        let mut writer = stream.clone();
+        assert_eq!(writer.write(b"hel").await.unwrap(), 3);
+        assert!(stream.finish().is_err());
+        assert_eq!(writer.write(b"lo").await.unwrap(), 2);
+        stream.finish().unwrap();
+        let err = writer.write(b"!").await.unwrap_err();
+        assert!(err.to_string().contains("past the expected 5 bytes"));
+
+        let mut stream = ExpectingOutputStream::new("hello");
+        let err = stream.write(b"help").await.unwrap_err();
+        assert!(err.to_string().contains("offset 3"));
+    }
+
+    #[tokio::test]
+    async fn chunked_input_stream_keeps_chunk_boundaries() {
+        let mut stream = ChunkedInputStream::new(vec![b"abc".to_vec(), vec![], b"defgh".to_vec()]);
+        let mut buf = [0; 4];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+        assert_eq!(stream.num_ready_bytes().await.unwrap(), 5);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (4, false));
+        assert_eq!(&buf, b"defg");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (1, true));
+        assert_eq!(buf[0], b'h');
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn jitter_input_stream_is_reproducible() {
+        async fn run(seed: u64) -> Vec<(u64, Vec<u8>)> {
+            let clock = TickClock::new(0);
+            let mut stream = JitterInputStream::new(
+                ReadPipe::from("hello, jittery world"),
+                clock.clone(),
+                Duration::from_nanos(100),
+                seed,
+            )
+            .split_chunks();
+            let mut reads = Vec::new();
+            let mut buf = [0; 8];
+            for now in (0..).step_by(10) {
+                clock.set(now);
+                let (n, end) = stream.read(&mut buf).await.unwrap();
+                if n != 0 {
+                    reads.push((now, buf[..n as usize].to_vec()));
+                }
+                if end {
+                    break;
+                }
+            }
+            reads
+        }
+
+        let reads = run(1).await;
+        assert_eq!(
+            reads
+                .iter()
+                .flat_map(|(_, bytes)| bytes)
+                .copied()
+                .collect::<Vec<u8>>(),
+            b"hello, jittery world"
+        );
+        assert_eq!(run(1).await, reads);
+    }
+
+    #[tokio::test]
+    async fn dropped_jitter_read_loses_nothing() {
+        let chunks = Arc::new(Mutex::new(VecDeque::from([b"early".to_vec()])));
+        let mut stream =
+            JitterInputStream::new(Gated(chunks.clone()), TickClock::new(0), Duration::ZERO, 7)
+                .split_chunks();
+        let mut buf = [0; 16];
+        let (n, _) = stream.read(&mut buf).await.unwrap();
+        let mut contents = buf[..n as usize].to_vec();
+        assert!(poll_once(&mut stream.read(&mut buf)).await.is_pending());
+
+        chunks.lock().unwrap().push_back(b" late".to_vec());
+        let (n, _) = stream.read(&mut buf).await.unwrap();
+        contents.extend_from_slice(&buf[..n as usize]);
+        assert_eq!(contents, b"early late");
+    }
+
+    #[tokio::test]
+    async fn jitter_max_buffer_stops_reading_ahead() {
+        let source = ReadPipe::from("0123456789");
+        let mut stream =
+            JitterInputStream::new(source.clone(), TickClock::new(0), Duration::from_secs(1), 3)
+                .with_max_buffer(4);
+        let mut delivered = 0;
+        let mut buf = [0; 16];
+        for _ in 0..3 {
+            delivered += stream.read(&mut buf).await.unwrap().0;
+        }
+        let taken = 10 - source.num_ready_bytes().await.unwrap();
+        assert!(taken >= 4);
+        assert!(taken - delivered <= 4);
+    }
+}
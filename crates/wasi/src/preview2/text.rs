@@ -0,0 +1,892 @@
+//! Text stream adapters.
+//!
+//! These wrap byte streams to handle conventions that only matter when the
+//! bytes are text, such as the UTF-8 byte order mark some Windows tools emit
+//! and expect, or to rewrite text as it flows through, such as masking
+//! secrets in captured output, or to tell whether output is text at all.
+
+use crate::preview2::stream::{
+    forward_input_stream, forward_output_stream, InputStream, OutputStream,
+};
+use anyhow::Error;
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+
+/// The UTF-8 encoding of U+FEFF, used as a byte order mark.
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// An output stream that writes a UTF-8 byte order mark before anything else.
+///
+/// The mark is written exactly once, ahead of the first non-empty write. The
+/// counts returned from writes cover only the guest's own bytes.
+pub struct BomOutputStream<T> {
+    inner: T,
+    /// How much of the byte order mark has been written so far.
+    bom_written: usize,
+}
+
+impl<T: OutputStream> BomOutputStream<T> {
+    /// Create a stream writing to `inner`, with the mark not yet written.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            bom_written: 0,
+        }
+    }
+
+    /// Write whatever remains of the byte order mark, returning whether it
+    /// has now been fully written.
+    async fn write_bom(&mut self) -> Result<bool, Error> {
+        while self.bom_written < UTF8_BOM.len() {
+            let n = self.inner.write(&UTF8_BOM[self.bom_written..]).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            self.bom_written += usize::try_from(n)?;
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for BomOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        if buf.is_empty() || !self.write_bom().await? {
+            return Ok(0);
+        }
+        self.inner.write(buf).await
+    }
+
+    async fn write_zeroes(&mut self, nelem: u64) -> Result<u64, Error> {
+        if nelem == 0 || !self.write_bom().await? {
+            return Ok(0);
+        }
+        self.inner.write_zeroes(nelem).await
+    }
+}
+
+/// An input stream that removes a UTF-8 byte order mark from the start of its
+/// inner stream.
+///
+/// The mark is recognized even when it arrives split across several reads.
+/// If the stream does not start with a mark, its bytes are delivered
+/// unchanged.
+pub struct BomStrippingInputStream<T> {
+    inner: T,
+    /// Leading bytes read while looking for the mark, which turned out not to
+    /// be one and still need to be delivered.
+    prefix: Vec<u8>,
+    /// Whether the start of the stream is still being examined.
+    checking: bool,
+    /// Whether the inner stream ended while the start was being examined.
+    ended: bool,
+}
+
+impl<T: InputStream> BomStrippingInputStream<T> {
+    /// Create a stream reading `inner`, which may start with a mark.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            prefix: Vec::new(),
+            checking: true,
+            ended: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for BomStrippingInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        if self.checking {
+            while self.prefix.len() < UTF8_BOM.len() {
+                let mut bytes = [0; 3];
+                let want = UTF8_BOM.len() - self.prefix.len();
+                let (n, end) = self.inner.read(&mut bytes[..want]).await?;
+                self.prefix.extend_from_slice(&bytes[..usize::try_from(n)?]);
+                if !UTF8_BOM.starts_with(&self.prefix) {
+                    break;
+                }
+                if end {
+                    self.ended = true;
+                    break;
+                }
+                if n == 0 {
+                    // Not enough input yet to tell whether there is a mark.
+                    return Ok((0, false));
+                }
+            }
+            if self.prefix == UTF8_BOM {
+                self.prefix.clear();
+            }
+            self.checking = false;
+        }
+
+        if !self.prefix.is_empty() {
+            let n = buf.len().min(self.prefix.len());
+            buf[..n].copy_from_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Ok((n.try_into()?, self.ended && self.prefix.is_empty()));
+        }
+        if self.ended {
+            return Ok((0, true));
+        }
+        self.inner.read(buf).await
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let ready = u64::try_from(self.prefix.len())? + self.inner.num_ready_bytes().await?;
+        if self.checking {
+            // The first bytes may yet turn out to be a mark, which is never
+            // delivered.
+            return Ok(ready.saturating_sub(UTF8_BOM.len().try_into()?));
+        }
+        Ok(ready)
+    }
+}
+
+/// An output stream that replaces every occurrence of a byte pattern with a
+/// replacement before forwarding to an inner stream.
+///
+/// Occurrences split across several writes are replaced too: up to
+/// `pattern.len() - 1` bytes at the end of each write are held back until
+/// the next write shows whether they start an occurrence. Call
+/// [`finish`](Self::finish), or shut the stream down, once the guest is done
+/// writing to forward the bytes still held.
+pub struct ReplaceOutputStream<T> {
+    inner: T,
+    /// Patterns and their replacements, longest pattern first.
+    rules: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The length of the longest pattern.
+    max_len: usize,
+    /// Bytes written but not yet searched, because they might be the start of
+    /// an occurrence.
+    pending: Vec<u8>,
+    /// Rewritten bytes that the inner stream hasn't accepted yet.
+    output: Vec<u8>,
+    max_buffer: usize,
+}
+
+impl<T: OutputStream> ReplaceOutputStream<T> {
+    /// # Panics
+    ///
+    /// Panics if `pattern` is empty.
+    pub fn new(inner: T, pattern: impl Into<Vec<u8>>, replacement: impl Into<Vec<u8>>) -> Self {
+        Self::with_rules(inner, vec![(pattern.into(), replacement.into())])
+    }
+
+    /// Create a stream replacing several patterns. Where patterns overlap,
+    /// the longest one that matches is replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any pattern is empty.
+    fn with_rules(inner: T, mut rules: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        assert!(
+            rules.iter().all(|(pattern, _)| !pattern.is_empty()),
+            "pattern must not be empty"
+        );
+        rules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        let max_len = rules.first().map_or(0, |(pattern, _)| pattern.len());
+        Self {
+            inner,
+            rules,
+            max_len,
+            pending: Vec::new(),
+            output: Vec::new(),
+            max_buffer: usize::MAX,
+        }
+    }
+
+    /// Hold at most `limit` written bytes. A write accepts only as many bytes
+    /// as fit, and none while earlier output is still waiting for the inner
+    /// stream. Replacements longer than their patterns can still take the
+    /// held output past the limit by however much they grow one write.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is shorter than the longest pattern, which has to
+    /// fit to be found.
+    pub fn with_max_buffer(mut self, limit: usize) -> Self {
+        assert!(
+            limit >= self.max_len.max(1),
+            "max buffer must fit the longest pattern"
+        );
+        self.max_buffer = limit;
+        self
+    }
+
+    /// Move everything in `pending` that can't be the start of an occurrence
+    /// to `output`, replacing occurrences on the way.
+    fn rewrite(&mut self, at_end: bool) {
+        let limit = if at_end {
+            self.pending.len()
+        } else {
+            // Every pattern fits in the bytes from before `limit` onwards, so
+            // those bytes can be matched against all of them.
+            self.pending
+                .len()
+                .saturating_sub(self.max_len.saturating_sub(1))
+        };
+        let mut i = 0;
+        while i < limit {
+            let rest = &self.pending[i..];
+            match self
+                .rules
+                .iter()
+                .find(|(pattern, _)| rest.starts_with(pattern))
+            {
+                Some((pattern, replacement)) => {
+                    self.output.extend_from_slice(replacement);
+                    i += pattern.len();
+                }
+                None => {
+                    self.output.push(self.pending[i]);
+                    i += 1;
+                }
+            }
+        }
+        self.pending.drain(..i);
+    }
+
+    /// Write as much of `output` as the inner stream accepts, returning
+    /// whether all of it was written.
+    async fn write_output(&mut self) -> Result<bool, Error> {
+        while !self.output.is_empty() {
+            let n = self.inner.write(&self.output).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            self.output.drain(..usize::try_from(n)?);
+        }
+        Ok(true)
+    }
+
+    /// Forward the bytes still held back.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        self.rewrite(true);
+        if !self.write_output().await? {
+            return Err(anyhow::anyhow!("output could not be written"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for ReplaceOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        // Hold off on accepting more while earlier output is still waiting,
+        // so a blocked inner stream isn't buffered up without bound.
+        if !self.write_output().await? {
+            return Ok(0);
+        }
+        let n = buf
+            .len()
+            .min(self.max_buffer.saturating_sub(self.pending.len()));
+        self.pending.extend_from_slice(&buf[..n]);
+        self.rewrite(false);
+        self.write_output().await?;
+        Ok(n.try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.finish().await?;
+        self.inner.shutdown().await
+    }
+}
+
+/// What [`RedactingOutputStream`] writes in place of a secret.
+pub const REDACTED: &[u8] = b"****";
+
+/// An output stream that masks secrets in the guest's output before it
+/// reaches an inner stream, such as a log capture.
+///
+/// Every occurrence of a secret is replaced with [`REDACTED`], whatever the
+/// secret's length, including occurrences split across several writes. As
+/// with [`ReplaceOutputStream`], the last bytes written are held back until
+/// the next write or until the stream is [finished](Self::finish) or shut
+/// down, since they might be the start of a secret.
+pub struct RedactingOutputStream<T> {
+    inner: ReplaceOutputStream<T>,
+}
+
+impl<T: OutputStream> RedactingOutputStream<T> {
+    /// # Panics
+    ///
+    /// Panics if any secret is empty.
+    pub fn new<S: Into<Vec<u8>>>(inner: T, secrets: impl IntoIterator<Item = S>) -> Self {
+        let rules = secrets
+            .into_iter()
+            .map(|secret| (secret.into(), REDACTED.to_vec()))
+            .collect();
+        Self {
+            inner: ReplaceOutputStream::with_rules(inner, rules),
+        }
+    }
+
+    /// Hold at most `limit` written bytes, as with
+    /// [`ReplaceOutputStream::with_max_buffer`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is shorter than the longest secret.
+    pub fn with_max_buffer(self, limit: usize) -> Self {
+        Self {
+            inner: self.inner.with_max_buffer(limit),
+        }
+    }
+
+    /// Forward the bytes still held back.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        self.inner.finish().await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for RedactingOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        self.inner.write(buf).await
+    }
+}
+
+/// The standard base64 alphabet, from RFC 4648.
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append the base64 encoding of `bytes`, which holds at most three bytes, to
+/// `output`, padding it if `bytes` is shorter than three.
+fn encode_base64_group(bytes: &[u8], output: &mut Vec<u8>) {
+    let mut group = [0; 3];
+    group[..bytes.len()].copy_from_slice(bytes);
+    let bits = (u32::from(group[0]) << 16) | (u32::from(group[1]) << 8) | u32::from(group[2]);
+    for i in 0..4 {
+        if i <= bytes.len() {
+            output.push(BASE64_ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize]);
+        } else {
+            output.push(b'=');
+        }
+    }
+}
+
+/// An output stream that base64-encodes everything written to it before
+/// forwarding it to an inner stream, e.g. to carry binary output over a
+/// text-only channel.
+///
+/// Base64 encodes three bytes at a time, so up to two bytes at the end of each
+/// write are held back until the next write completes their group. Call
+/// [`finish`](Self::finish), or shut the stream down, once the guest is done
+/// writing to forward the final, padded group.
+pub struct Base64OutputStream<T> {
+    inner: T,
+    /// Bytes written but not yet encoded, fewer than three.
+    partial: Vec<u8>,
+    /// Encoded bytes that the inner stream hasn't accepted yet.
+    output: Vec<u8>,
+    max_buffer: usize,
+}
+
+impl<T: OutputStream> Base64OutputStream<T> {
+    /// Create a stream writing the Base64 encoding of its input to `inner`,
+    /// with no limit on the encoded bytes held.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            partial: Vec::with_capacity(3),
+            output: Vec::new(),
+            max_buffer: usize::MAX,
+        }
+    }
+
+    /// Hold at most `limit` encoded bytes. A write accepts only as many
+    /// bytes as fit once encoded, and none while earlier output is still
+    /// waiting for the inner stream.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is less than four, the length of one encoded group.
+    pub fn with_max_buffer(mut self, limit: usize) -> Self {
+        assert!(limit >= 4, "max buffer must fit an encoded group");
+        self.max_buffer = limit;
+        self
+    }
+
+    /// Write as much of `output` as the inner stream accepts, returning
+    /// whether all of it was written.
+    async fn write_output(&mut self) -> Result<bool, Error> {
+        while !self.output.is_empty() {
+            let n = self.inner.write(&self.output).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            self.output.drain(..usize::try_from(n)?);
+        }
+        Ok(true)
+    }
+
+    /// Forward the final group, padded if the total length written isn't a
+    /// multiple of three.
+    pub async fn finish(&mut self) -> Result<(), Error> {
+        if !self.partial.is_empty() {
+            encode_base64_group(&self.partial, &mut self.output);
+            self.partial.clear();
+        }
+        if !self.write_output().await? {
+            return Err(anyhow::anyhow!("output could not be written"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for Base64OutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        // Hold off on accepting more while earlier output is still waiting,
+        // so a blocked inner stream isn't buffered up without bound.
+        if !self.write_output().await? {
+            return Ok(0);
+        }
+        // Every three bytes, counting the held partial group, encode to four.
+        let n = buf
+            .len()
+            .min((self.max_buffer / 4 * 3 + 2).saturating_sub(self.partial.len()));
+        let buf = &buf[..n];
+        let mut rest = buf;
+        if !self.partial.is_empty() {
+            let n = rest.len().min(3 - self.partial.len());
+            self.partial.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.partial.len() < 3 {
+                return Ok(buf.len().try_into()?);
+            }
+            encode_base64_group(&self.partial, &mut self.output);
+            self.partial.clear();
+        }
+        let mut groups = rest.chunks_exact(3);
+        for group in &mut groups {
+            encode_base64_group(group, &mut self.output);
+        }
+        self.partial.extend_from_slice(groups.remainder());
+        self.write_output().await?;
+        Ok(buf.len().try_into()?)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.finish().await?;
+        self.inner.shutdown().await
+    }
+}
+
+/// The default [`SniffingOutputStream::sniff_len`], matching the amount of a
+/// file git looks at to decide whether it is binary.
+pub const DEFAULT_SNIFF_LEN: usize = 8000;
+
+#[derive(Default)]
+struct SniffState {
+    /// The start of the output, up to the sniff length.
+    sample: Vec<u8>,
+    is_binary: Option<bool>,
+}
+
+impl SniffState {
+    /// Classify the output from its sample. Until `complete`, only a sample
+    /// that is certainly binary gives an answer.
+    fn classify(&mut self, complete: bool) {
+        if self.is_binary.is_some() {
+            return;
+        }
+        let binary = self.sample.contains(&0)
+            || matches!(std::str::from_utf8(&self.sample), Err(e) if e.error_len().is_some());
+        if binary {
+            self.is_binary = Some(true);
+        } else if complete {
+            // A multi-byte character cut off at the end of the sample doesn't
+            // make it binary.
+            self.is_binary = Some(false);
+        }
+    }
+}
+
+/// A handle to the classification made by a [`SniffingOutputStream`].
+///
+/// Clones observe the same classification, so a handle can be kept after the
+/// stream itself has been handed to a `WasiCtx`.
+#[derive(Clone, Default)]
+pub struct Sniff(Arc<Mutex<SniffState>>);
+
+impl Sniff {
+    /// Whether the output looks binary rather than text, or `None` if not
+    /// enough has been written to tell yet.
+    pub fn is_binary(&self) -> Option<bool> {
+        self.0.lock().unwrap().is_binary
+    }
+}
+
+/// An output stream that guesses whether the output passing through it is
+/// text or binary, e.g. so a capture harness can show text inline and binary
+/// output as a hex dump.
+///
+/// The guess is based on the first [`sniff_len`](Self::sniff_len) bytes: the
+/// output is binary if they contain a NUL byte or are not valid UTF-8, and
+/// text otherwise. A certain answer is available as soon as a NUL or invalid
+/// byte is written; otherwise the answer is text once the sniff length is
+/// reached, or once the stream is shut down with less output than that.
+pub struct SniffingOutputStream<T> {
+    inner: T,
+    sniff: Sniff,
+    sniff_len: usize,
+}
+
+impl<T: OutputStream> SniffingOutputStream<T> {
+    /// Create a stream writing to `inner` that looks at the first
+    /// [`DEFAULT_SNIFF_LEN`] bytes.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            sniff: Sniff::default(),
+            sniff_len: DEFAULT_SNIFF_LEN,
+        }
+    }
+
+    /// Set how many bytes from the start of the output are looked at.
+    /// Defaults to [`DEFAULT_SNIFF_LEN`].
+    pub fn sniff_len(mut self, sniff_len: usize) -> Self {
+        self.sniff_len = sniff_len;
+        self
+    }
+
+    /// Whether the output looks binary rather than text, or `None` if not
+    /// enough has been written to tell yet.
+    pub fn is_binary(&self) -> Option<bool> {
+        self.sniff.is_binary()
+    }
+
+    /// A handle to this stream's classification.
+    pub fn sniff(&self) -> Sniff {
+        self.sniff.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for SniffingOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        let mut state = self.sniff.0.lock().unwrap();
+        if state.is_binary.is_none() {
+            let take = usize::try_from(n)?.min(self.sniff_len - state.sample.len());
+            state.sample.extend_from_slice(&buf[..take]);
+            let complete = state.sample.len() >= self.sniff_len;
+            state.classify(complete);
+        }
+        Ok(n)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.sniff.0.lock().unwrap().classify(true);
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::pipe::{pipe, ReadPipe, WritePipe};
+
+    #[tokio::test]
+    async fn bom_written_once() {
+        let capture = WritePipe::new_in_memory();
+        let mut stream = BomOutputStream::new(capture.clone());
+        assert_eq!(stream.write(b"").await.unwrap(), 0);
+        assert_eq!(stream.write(b"one").await.unwrap(), 3);
+        assert_eq!(stream.write(b"two").await.unwrap(), 3);
+        assert_eq!(capture.take_contents(), b"\xEF\xBB\xBFonetwo");
+    }
+
+    #[tokio::test]
+    async fn bom_stripped_across_reads() {
+        let (input, mut output) = pipe(8);
+        let mut stream = BomStrippingInputStream::new(input);
+        let mut buf = [0; 16];
+
+        output.write(b"\xEF").await.unwrap();
+        output.write(b"\xBB").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+        output.write(b"\xBFhi").await.unwrap();
+        drop(output);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"hi");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn no_bom_passes_through() {
+        let mut stream = BomStrippingInputStream::new(ReadPipe::from("\u{EF}x"));
+        let mut buf = [0; 16];
+        let mut contents = Vec::new();
+        loop {
+            let (n, end) = stream.read(&mut buf).await.unwrap();
+            contents.extend_from_slice(&buf[..n as usize]);
+            if end {
+                break;
+            }
+        }
+        assert_eq!(contents, "\u{EF}x".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn replace_across_writes() {
+        let capture = WritePipe::new_in_memory();
+        let mut stream = ReplaceOutputStream::new(capture.clone(), "{{name}}", "world");
+        stream.write(b"hello, {{na").await.unwrap();
+        stream.write(b"me}}! {").await.unwrap();
+        // The last bytes could still be the start of a pattern, so they are
+        // held back until the stream is finished.
+        assert_eq!(capture.take_contents(), b"hello, world");
+        stream.finish().await.unwrap();
+        assert_eq!(capture.take_contents(), b"! {");
+    }
+
+    #[tokio::test]
+    async fn redact_secrets() {
+        let capture = WritePipe::new_in_memory();
+        let mut stream =
+            RedactingOutputStream::new(capture.clone(), ["hunter2", "hunter2-admin", "pw"]);
+        stream.write(b"token=hun").await.unwrap();
+        stream.write(b"ter2-admin user=hunter2 ").await.unwrap();
+        stream.write(b"p").await.unwrap();
+        stream.write(b"w").await.unwrap();
+        stream.finish().await.unwrap();
+        assert_eq!(capture.take_contents(), b"token=**** user=**** ****");
+    }
+
+    #[tokio::test]
+    async fn base64_across_writes() {
+        for (input, encoded) in [
+            (&b""[..], &b""[..]),
+            (b"f", b"Zg=="),
+            (b"fo", b"Zm8="),
+            (b"foo", b"Zm9v"),
+            (b"foobar", b"Zm9vYmFy"),
+            (b"\xFF\xFE\x00\x10binary", b"//4AEGJpbmFyeQ=="),
+        ] {
+            // Write a byte at a time, so groups straddle writes.
+            let capture = WritePipe::new_in_memory();
+            let mut stream = Base64OutputStream::new(capture.clone());
+            for byte in input {
+                assert_eq!(stream.write(&[*byte]).await.unwrap(), 1);
+            }
+            stream.finish().await.unwrap();
+            assert_eq!(capture.take_contents(), encoded);
+        }
+    }
+
+    #[tokio::test]
+    async fn sniffing_tells_text_from_binary() {
+        let mut text = SniffingOutputStream::new(WritePipe::new_in_memory()).sniff_len(8);
+        let sniff = text.sniff();
+        text.write("héllo".as_bytes()).await.unwrap();
+        assert_eq!(sniff.is_binary(), None);
+        // The sample ends partway through `é`, which is still text.
+        text.write("wörld".as_bytes()).await.unwrap();
+        assert_eq!(sniff.is_binary(), Some(false));
+        text.write(b"\0").await.unwrap();
+        assert_eq!(sniff.is_binary(), Some(false));
+
+        let mut binary = SniffingOutputStream::new(WritePipe::new_in_memory());
+        binary.write(b"ok\xFF").await.unwrap();
+        assert_eq!(binary.is_binary(), Some(true));
+
+        let mut nul = SniffingOutputStream::new(WritePipe::new_in_memory());
+        nul.write(b"a\0b").await.unwrap();
+        assert_eq!(nul.is_binary(), Some(true));
+
+        let mut short = SniffingOutputStream::new(WritePipe::new_in_memory());
+        short.write(b"hi").await.unwrap();
+        assert_eq!(short.is_binary(), None);
+        short.shutdown().await.unwrap();
+        assert_eq!(short.is_binary(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn replace_max_buffer_applies_back_pressure() {
+        let (mut input, output) = pipe(1);
+        let mut stream = ReplaceOutputStream::new(output, "ab", "X").with_max_buffer(4);
+        assert_eq!(stream.write(b"123456").await.unwrap(), 4);
+        // The inner pipe is now full, so this output is held.
+        assert_eq!(stream.write(b"56").await.unwrap(), 2);
+        assert_eq!(stream.write(b"7").await.unwrap(), 0);
+
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (3, false));
+        assert_eq!(&buf[..3], b"123");
+        assert_eq!(stream.write(b"7").await.unwrap(), 1);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"45");
+    }
+
+    #[tokio::test]
+    async fn base64_max_buffer_bounds_encoded_output() {
+        let (mut input, output) = pipe(1);
+        let mut stream = Base64OutputStream::new(output).with_max_buffer(8);
+        assert_eq!(stream.write(b"abcdefghij").await.unwrap(), 8);
+        assert_eq!(stream.write(b"ij").await.unwrap(), 2);
+        assert_eq!(stream.write(b"k").await.unwrap(), 0);
+
+        let mut buf = [0; 16];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (8, false));
+        assert_eq!(&buf[..8], b"YWJjZGVm");
+        assert_eq!(stream.write(b"k").await.unwrap(), 1);
+        assert_eq!(input.read(&mut buf).await.unwrap(), (4, false));
+        assert_eq!(&buf[..4], b"Z2hp");
+    }
+
+    #[tokio::test]
+    async fn ready_bytes_exclude_the_mark() {
+        let (input, mut output) = pipe(4);
+        let mut input = BomStrippingInputStream::new(input);
+        output.write(b"\xEF\xBB").await.unwrap();
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 0);
+        output.write(b"\xBFhi").await.unwrap();
+        assert_eq!(input.num_ready_bytes().await.unwrap(), 2);
+        let mut buf = [0; 8];
+        assert_eq!(input.read(&mut buf).await.unwrap(), (2, false));
+        assert_eq!(&buf[..2], b"hi");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pollable_write_is_forwarded() {
+        use rustix::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+        /// An output stream backed by a host file descriptor.
+        struct Pollable(OwnedFd);
+
+        #[async_trait::async_trait]
+        impl OutputStream for Pollable {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            fn pollable_write(&self) -> Option<BorrowedFd> {
+                Some(self.0.as_fd())
+            }
+            async fn writable(&self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let (_reader, writer) = rustix::io::pipe().unwrap();
+        let fd = writer.as_raw_fd();
+        let stream = Base64OutputStream::new(ReplaceOutputStream::new(
+            RedactingOutputStream::new(Pollable(writer), ["secret"]),
+            "a",
+            "b",
+        ));
+        assert_eq!(stream.pollable_write().unwrap().as_raw_fd(), fd);
+    }
+}
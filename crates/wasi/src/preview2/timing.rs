@@ -0,0 +1,267 @@
+//! Time limits for streams.
+//!
+//! The wrappers in this module stop an inner stream from keeping the guest
+//! waiting forever, by ending an input stream that has been idle for too long
+//! or failing the writes an output stream can't take by a deadline. Time is
+//! measured on a [`WasiMonotonicClock`], so tests can drive it.
+
+use crate::preview2::clocks::{until_deadline, WasiMonotonicClock};
+use crate::preview2::pipe;
+use crate::preview2::stream::{
+    forward_input_stream, forward_output_stream, InputStream, OutputStream,
+};
+use anyhow::Error;
+use std::any::Any;
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// An input stream that ends once an inner stream has gone without data for
+/// longer than an idle timeout, e.g. to reap abandoned sessions.
+///
+/// The idle time, measured on the given clock, restarts whenever a read
+/// returns data. A read that finds no data after the timeout has passed
+/// reports the end of the stream, and so does every read after it. The
+/// timeout is only checked when the guest reads, so a guest blocked in
+/// `poll-oneoff` on the inner stream's host handle keeps waiting.
+pub struct IdleTimeoutInputStream<T> {
+    inner: T,
+    clock: Box<dyn WasiMonotonicClock>,
+    idle_timeout: u64,
+    /// When data last arrived, on `clock`.
+    last_data: u64,
+    timed_out: bool,
+}
+
+impl<T: InputStream> IdleTimeoutInputStream<T> {
+    /// Create a stream reading `inner` that ends once no data has arrived for
+    /// `idle_timeout`, as measured on `clock`. The idle time starts now.
+    pub fn new(inner: T, clock: impl WasiMonotonicClock + 'static, idle_timeout: Duration) -> Self {
+        let last_data = clock.now();
+        Self {
+            inner,
+            clock: Box::new(clock),
+            idle_timeout: idle_timeout.as_nanos().try_into().unwrap_or(u64::MAX),
+            last_data,
+            timed_out: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: InputStream + Any> InputStream for IdleTimeoutInputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_input_stream!(inner);
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(u64, bool), Error> {
+        if self.timed_out {
+            return Ok((0, true));
+        }
+        let (n, end) = self.inner.read(buf).await?;
+        let now = self.clock.now();
+        if n != 0 {
+            self.last_data = now;
+        } else if !end && !buf.is_empty() && now.saturating_sub(self.last_data) > self.idle_timeout
+        {
+            tracing::debug!("input stream idle for too long, closing");
+            self.timed_out = true;
+            return Ok((0, true));
+        }
+        Ok((n, end))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        if self.timed_out {
+            return Ok(0);
+        }
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        if self.timed_out {
+            // Reads now report the end of the stream straight away.
+            return Ok(());
+        }
+        self.inner.readable().await
+    }
+}
+
+/// An output stream that gives up on an inner stream that can't take more
+/// data by a deadline, e.g. so that a guest can implement write timeouts.
+///
+/// The deadline is an instant on the given clock, such as one a guest
+/// computed from its monotonic clock. Until it passes, the stream behaves
+/// like the inner stream. Afterwards, a write the inner stream accepts no
+/// bytes of, and a wait for writability that has not finished, fail with
+/// [`pipe::Error::WouldBlock`] instead of reporting that nothing was written or
+/// waiting on.
+pub struct DeadlineOutputStream<T> {
+    inner: T,
+    clock: Box<dyn WasiMonotonicClock>,
+    deadline: u64,
+}
+
+impl<T: OutputStream> DeadlineOutputStream<T> {
+    /// Create a stream writing to `inner` until `deadline`, an instant in
+    /// nanoseconds on `clock`, the same units as [`WasiMonotonicClock::now`].
+    pub fn new(inner: T, clock: impl WasiMonotonicClock + 'static, deadline: u64) -> Self {
+        Self {
+            inner,
+            clock: Box::new(clock),
+            deadline,
+        }
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        self.clock.now() >= self.deadline
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: OutputStream + Any> OutputStream for DeadlineOutputStream<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    forward_output_stream!(inner);
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.inner.shutdown().await
+    }
+
+    async fn sync_data(&mut self) -> Result<(), Error> {
+        self.inner.sync_data().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Error> {
+        self.inner.sync_all().await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let n = self.inner.write(buf).await?;
+        if n == 0 && !buf.is_empty() && self.is_past_deadline() {
+            return Err(pipe::Error::WouldBlock.into());
+        }
+        Ok(n)
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        until_deadline(self.inner.writable(), &*self.clock, self.deadline)
+            .await
+            .unwrap_or_else(|| Err(pipe::Error::WouldBlock.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::preview2::clocks::TickClock;
+    use crate::preview2::pipe::{pipe, ReadPipe};
+    use crate::preview2::stream::test_util::poll_once;
+    use std::task::Poll;
+
+    #[tokio::test]
+    async fn idle_timeout_input_stream() {
+        let clock = TickClock::new(0);
+        let (input, mut output) = pipe(4);
+        let mut stream =
+            IdleTimeoutInputStream::new(input, clock.clone(), Duration::from_nanos(100));
+        let mut buf = [0; 16];
+
+        clock.set(100);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+        output.write(b"data").await.unwrap();
+        clock.set(150);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (4, false));
+
+        // The idle time restarted when the data arrived.
+        clock.set(250);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, false));
+        clock.set(251);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+        output.write(b"late").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), (0, true));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_input_stream_readable_after_timeout() {
+        struct NeverReadable;
+
+        #[async_trait::async_trait]
+        impl InputStream for NeverReadable {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            async fn read(&mut self, _buf: &mut [u8]) -> Result<(u64, bool), Error> {
+                Ok((0, false))
+            }
+            async fn readable(&self) -> Result<(), Error> {
+                std::future::pending().await
+            }
+        }
+
+        let clock = TickClock::new(0);
+        let mut stream =
+            IdleTimeoutInputStream::new(NeverReadable, clock.clone(), Duration::from_nanos(100));
+        assert!(poll_once(&mut stream.readable()).await.is_pending());
+        clock.set(101);
+        assert_eq!(stream.read(&mut [0; 4]).await.unwrap(), (0, true));
+        assert!(matches!(
+            poll_once(&mut stream.readable()).await,
+            Poll::Ready(Ok(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deadline_output_stream_write() {
+        let clock = TickClock::new(0);
+        let (mut input, output) = pipe(1);
+        let mut output = DeadlineOutputStream::new(output, clock.clone(), 100);
+        assert_eq!(output.write(b"a").await.unwrap(), 1);
+        // Before the deadline, a full pipe just accepts nothing.
+        assert_eq!(output.write(b"b").await.unwrap(), 0);
+
+        clock.set(100);
+        let err = output.write(b"b").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<pipe::Error>(),
+            Some(pipe::Error::WouldBlock)
+        ));
+
+        // Data the inner stream can take is still written after the deadline.
+        input.read(&mut [0; 1]).await.unwrap();
+        assert_eq!(output.write(b"b").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn deadline_output_stream_writable() {
+        struct NeverWritable;
+
+        #[async_trait::async_trait]
+        impl OutputStream for NeverWritable {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+            async fn writable(&self) -> Result<(), Error> {
+                std::future::pending().await
+            }
+        }
+
+        let clock = TickClock::new(0);
+        let output = DeadlineOutputStream::new(NeverWritable, clock.clone(), 100);
+        let mut writable = output.writable();
+        assert!(poll_once(&mut writable).await.is_pending());
+        clock.set(100);
+        match poll_once(&mut writable).await {
+            Poll::Ready(Err(err)) => {
+                assert!(matches!(
+                    err.downcast_ref::<pipe::Error>(),
+                    Some(pipe::Error::WouldBlock)
+                ))
+            }
+            _ => panic!("writable should fail after the deadline"),
+        }
+    }
+}